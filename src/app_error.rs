@@ -4,28 +4,33 @@ use std::{array::TryFromSliceError, fmt, io::Error};
 pub enum AppError {
     IOError(String),
     TryFromSliceError(String),
-    FileTooShort,
     SerializeError(String),
     IncompatibleChunkSize(String),
-    IncompatibleDataSize(String),
-    IndexCorrupted,
+    IncompatibleDelta(String),
+    WrongFileKind(String),
+    UnsupportedFormat(String),
+    TruncatedFile(String),
+    CorruptedFile(String),
+    IncompatibleHash(String),
+    ChunkStoreError(String),
+    UnsupportedFile(String),
 }
 
-const FILE_TOO_SHORT_DESCRIPTION: &str =
-    "File to small for processing, please provide bigger one or descrease the block size";
-
-const INDEX_CORRUPTED_DESRIPTION: &str = "Something went wrong please or signature file corrupted";
-
 impl std::error::Error for AppError {
     fn description(&self) -> &str {
         match self {
-            AppError::IOError(err_data) => &err_data,
-            AppError::TryFromSliceError(err_data) => &err_data,
-            AppError::FileTooShort => FILE_TOO_SHORT_DESCRIPTION,
-            AppError::SerializeError(err_data) => &err_data,
-            AppError::IncompatibleChunkSize(err_data) => &err_data,
-            AppError::IncompatibleDataSize(err_data) => &err_data,
-            AppError::IndexCorrupted => INDEX_CORRUPTED_DESRIPTION,
+            AppError::IOError(err_data) => err_data,
+            AppError::TryFromSliceError(err_data) => err_data,
+            AppError::SerializeError(err_data) => err_data,
+            AppError::IncompatibleChunkSize(err_data) => err_data,
+            AppError::IncompatibleDelta(err_data) => err_data,
+            AppError::WrongFileKind(err_data) => err_data,
+            AppError::UnsupportedFormat(err_data) => err_data,
+            AppError::TruncatedFile(err_data) => err_data,
+            AppError::CorruptedFile(err_data) => err_data,
+            AppError::IncompatibleHash(err_data) => err_data,
+            AppError::ChunkStoreError(err_data) => err_data,
+            AppError::UnsupportedFile(err_data) => err_data,
         }
     }
 }
@@ -33,13 +38,18 @@ impl std::error::Error for AppError {
 impl fmt::Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            AppError::IOError(err_data) => f.write_str(&err_data),
-            AppError::TryFromSliceError(err_data) => f.write_str(&err_data),
-            AppError::FileTooShort => f.write_str(FILE_TOO_SHORT_DESCRIPTION),
-            AppError::SerializeError(err_data) => f.write_str(&err_data),
-            AppError::IncompatibleChunkSize(err_data) => f.write_str(&err_data),
-            AppError::IncompatibleDataSize(err_data) => f.write_str(&err_data),
-            AppError::IndexCorrupted => f.write_str(INDEX_CORRUPTED_DESRIPTION),
+            AppError::IOError(err_data) => f.write_str(err_data),
+            AppError::TryFromSliceError(err_data) => f.write_str(err_data),
+            AppError::SerializeError(err_data) => f.write_str(err_data),
+            AppError::IncompatibleChunkSize(err_data) => f.write_str(err_data),
+            AppError::IncompatibleDelta(err_data) => f.write_str(err_data),
+            AppError::WrongFileKind(err_data) => f.write_str(err_data),
+            AppError::UnsupportedFormat(err_data) => f.write_str(err_data),
+            AppError::TruncatedFile(err_data) => f.write_str(err_data),
+            AppError::CorruptedFile(err_data) => f.write_str(err_data),
+            AppError::IncompatibleHash(err_data) => f.write_str(err_data),
+            AppError::ChunkStoreError(err_data) => f.write_str(err_data),
+            AppError::UnsupportedFile(err_data) => f.write_str(err_data),
         }
     }
 }
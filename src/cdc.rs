@@ -0,0 +1,176 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{app_error::AppError, chunk_processor::MAX_CHUNK_SIZE, rolling_hash::random_table};
+
+#[cfg(test)]
+use crate::test_support::test_data;
+
+// Gear hash values of the bytes, the seed only keeps them apart from the buzhash table
+const GEAR_TABLE: [u64; 256] = random_table(0x6765_6172);
+
+// Bounds of the average chunk size, the cut masks need a few bits on both sides of it
+const MIN_AVG_SIZE: usize = 64;
+
+/// Sizes of content-defined chunks: cut points are searched between `min_size` and `max_size`
+/// bytes, and are likelier past `avg_size` so that chunks stay close to it (FastCDC)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CdcParams {
+    pub min_size: usize,
+    pub avg_size: usize,
+    pub max_size: usize,
+}
+
+impl CdcParams {
+    pub fn new(min_size: usize, avg_size: usize, max_size: usize) -> Result<Self, AppError> {
+        if min_size == 0 || min_size > avg_size || avg_size > max_size {
+            return Err(AppError::IncompatibleChunkSize(format!(
+                "Content-defined chunk sizes must satisfy 0 < min <= avg <= max, got {}:{}:{}",
+                min_size, avg_size, max_size
+            )));
+        }
+
+        if avg_size < MIN_AVG_SIZE || max_size > MAX_CHUNK_SIZE {
+            return Err(AppError::IncompatibleChunkSize(format!(
+                "Content-defined chunks must average at least {} bytes and never exceed {} bytes",
+                MIN_AVG_SIZE, MAX_CHUNK_SIZE
+            )));
+        }
+
+        Ok(CdcParams {
+            min_size,
+            avg_size,
+            max_size,
+        })
+    }
+
+    /// Chunks between a quarter and four times `avg_size`
+    pub fn from_avg(avg_size: usize) -> Result<Self, AppError> {
+        CdcParams::new(avg_size / 4, avg_size, avg_size.saturating_mul(4))
+    }
+
+    // Stricter mask before the average size and looser one after it, both on the high bits of the hash
+    fn masks(&self) -> (u64, u64) {
+        let bits = self.avg_size.ilog2();
+
+        (!0 << (64 - (bits + 1)), !0 << (64 - (bits - 1)))
+    }
+
+    /// Length of the chunk starting `data`, which must hold at least `max_size` bytes unless it
+    /// is the end of the input
+    pub fn cut(&self, data: &[u8]) -> usize {
+        let len = data.len().min(self.max_size);
+
+        if len <= self.min_size {
+            return len;
+        }
+
+        let (mask_small, mask_large) = self.masks();
+        let normal = self.avg_size.min(len);
+        let mut hash: u64 = 0;
+
+        for (i, byte) in data.iter().enumerate().take(len).skip(self.min_size) {
+            hash = (hash << 1).wrapping_add(GEAR_TABLE[*byte as usize]);
+            let mask = if i < normal { mask_small } else { mask_large };
+
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+
+        len
+    }
+}
+
+impl fmt::Display for CdcParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.min_size, self.avg_size, self.max_size)
+    }
+}
+
+// Either the average size alone or MIN:AVG:MAX
+impl FromStr for CdcParams {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let sizes = value
+            .split(':')
+            .map(|size| size.parse::<usize>())
+            .collect::<Result<Vec<usize>, _>>()
+            .map_err(|err| format!("Invalid chunk size in {}: {}", value, err))?;
+
+        let params = match sizes[..] {
+            [avg_size] => CdcParams::from_avg(avg_size),
+            [min_size, avg_size, max_size] => CdcParams::new(min_size, avg_size, max_size),
+            _ => {
+                return Err(format!(
+                    "Expected AVG or MIN:AVG:MAX chunk sizes, got {}",
+                    value
+                ))
+            }
+        };
+
+        params.map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+fn content_chunks(data: &[u8], params: CdcParams) -> Vec<&[u8]> {
+    crate::chunk_iter::ChunkIter::new(data, 0)
+        .by_content(params)
+        .collect()
+}
+
+#[test]
+fn check_chunk_sizes_stay_in_bounds() {
+    let data = test_data(500_000, 21);
+    let params = CdcParams::new(256, 1024, 4096).unwrap();
+
+    let chunks = content_chunks(&data, params);
+    let (last, full) = chunks.split_last().unwrap();
+
+    assert!(full.iter().all(|chunk| (257..=4096).contains(&chunk.len())));
+    assert!(last.len() <= 4096);
+
+    // Average within a factor of two of the requested one
+    let avg = data.len() / chunks.len();
+    assert!((512..2048).contains(&avg), "average chunk size {}", avg);
+}
+
+#[test]
+fn check_cut_points_resync_after_insert() {
+    let data = test_data(200_000, 22);
+    let shifted = ["inserted at the front".as_bytes(), &data].concat();
+    let params = CdcParams::from_avg(1024).unwrap();
+
+    let chunks = content_chunks(&data, params);
+    let shifted_chunks = content_chunks(&shifted, params);
+
+    let shared = shifted_chunks
+        .iter()
+        .filter(|chunk| chunks.contains(chunk))
+        .count();
+    assert!(shared + 2 >= chunks.len(), "{} of {}", shared, chunks.len());
+}
+
+#[test]
+fn check_params_parsing() {
+    assert_eq!(
+        "1024".parse(),
+        Ok(CdcParams {
+            min_size: 256,
+            avg_size: 1024,
+            max_size: 4096
+        })
+    );
+    assert_eq!(
+        "100:200:300"
+            .parse::<CdcParams>()
+            .map(|params| params.to_string()),
+        Ok(String::from("100:200:300"))
+    );
+    assert!("300:200:100".parse::<CdcParams>().is_err());
+    assert!("16".parse::<CdcParams>().is_err());
+    assert!("1:2".parse::<CdcParams>().is_err());
+}
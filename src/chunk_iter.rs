@@ -1,34 +1,31 @@
-#[derive(Default)]
-pub struct DefaultIter {}
+use crate::cdc::CdcParams;
 
-#[derive(Default)]
-pub struct ByChunkIter {}
+#[cfg(test)]
+use crate::test_support::test_data;
 
-#[derive(Default)]
-pub struct ByByteIter {}
+pub struct DefaultIter {}
 
-impl ByByteIter {
-    const STEP: usize = 1;
+pub struct ByChunkIter {}
 
-    fn step(&self) -> usize {
-        ByByteIter::STEP
-    }
+// Variable length chunks cut where the content allows, `index` is then a byte offset
+pub struct ByContentIter {
+    params: CdcParams,
 }
 
 pub struct ChunkIter<'a, T> {
-    value: &'a Vec<u8>,
+    value: &'a [u8],
     chunk_size: usize,
     index: usize,
     type_iter: T,
 }
 
 impl<'a> ChunkIter<'a, DefaultIter> {
-    pub fn new(value: &'a Vec<u8>, chunk_size: usize) -> ChunkIter<'a, DefaultIter> {
+    pub fn new(value: &'a [u8], chunk_size: usize) -> ChunkIter<'a, DefaultIter> {
         ChunkIter::<'a, DefaultIter> {
             value,
             chunk_size,
             index: 0,
-            type_iter: DefaultIter::default(),
+            type_iter: DefaultIter {},
         }
     }
 
@@ -37,16 +34,16 @@ impl<'a> ChunkIter<'a, DefaultIter> {
             value: self.value,
             chunk_size: self.chunk_size,
             index: self.index,
-            type_iter: ByChunkIter::default(),
+            type_iter: ByChunkIter {},
         }
     }
 
-    pub fn by_byte(self) -> ChunkIter<'a, ByByteIter> {
-        ChunkIter::<'a, ByByteIter> {
+    pub fn by_content(self, params: CdcParams) -> ChunkIter<'a, ByContentIter> {
+        ChunkIter::<'a, ByContentIter> {
             value: self.value,
-            chunk_size: self.chunk_size,
+            chunk_size: params.max_size,
             index: self.index,
-            type_iter: ByByteIter::default(),
+            type_iter: ByContentIter { params },
         }
     }
 }
@@ -72,29 +69,20 @@ impl<'a> Iterator for ChunkIter<'a, ByChunkIter> {
     }
 }
 
-impl<'a> Iterator for ChunkIter<'a, ByByteIter> {
+impl<'a> Iterator for ChunkIter<'a, ByContentIter> {
     type Item = &'a [u8];
 
     fn next(&mut self) -> Option<Self::Item> {
-        let start_index = self.index * self.type_iter.step();
-        let end_index = start_index + self.chunk_size;
-        self.index += 1;
+        let rest = &self.value[self.index..];
 
-        if end_index <= self.value.len() {
-            Some(&self.value[start_index..end_index])
-        } else {
-            None
+        if rest.is_empty() {
+            return None;
         }
-    }
-}
 
-pub trait SkipChunk {
-    fn skip_chunks(&mut self, n: usize);
-}
+        let len = self.type_iter.params.cut(rest);
+        self.index += len;
 
-impl<'a> SkipChunk for ChunkIter<'a, ByByteIter> {
-    fn skip_chunks(&mut self, n: usize) {
-        self.index += n * self.chunk_size - 1;
+        Some(&rest[..len])
     }
 }
 
@@ -134,41 +122,15 @@ fn check_last_chunk_shorter_iteration() {
 }
 
 #[test]
-fn check_by_byte_chunk_iteration() {
-    let value: Vec<u8> = vec![1, 2, 3, 4, 1, 2, 3, 4, 1, 2, 3, 4, 1, 2, 3];
-    let chunk_size = 4;
-
-    let mut counter = 0;
-    for (i, el) in ChunkIter::new(&value, chunk_size).by_byte().enumerate() {
-        let mut expected = vec![1, 2, 3, 4];
-        let len = expected.len();
-        expected.rotate_left(i % len);
+fn check_content_iteration_covers_value() {
+    let value = test_data(100_000, 23);
+    let params = CdcParams::new(64, 256, 1024).unwrap();
 
-        assert_eq!(el.to_vec(), expected);
-        assert_eq!(counter, i);
-
-        counter += 1;
-    }
-
-    assert_eq!(counter, 12);
-}
-
-#[test]
-fn check_by_byte_chunk_iteration_with_skip() {
-    let value: Vec<u8> = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 1, 2, 3, 4, 5, 6];
-    let chunk_size = 4;
+    let chunks = ChunkIter::new(&value, 0)
+        .by_content(params)
+        .collect::<Vec<&[u8]>>();
 
-    let mut iter = ChunkIter::new(&value, chunk_size).by_byte();
-    assert_eq!(iter.next().unwrap().to_vec(), vec![1, 2, 3, 4]);
-    assert_eq!(iter.next().unwrap().to_vec(), vec![2, 3, 4, 5]);
-    assert_eq!(iter.next().unwrap().to_vec(), vec![3, 4, 5, 6]);
-    assert_eq!(iter.next().unwrap().to_vec(), vec![4, 5, 6, 7]);
-    assert_eq!(iter.next().unwrap().to_vec(), vec![5, 6, 7, 8]);
-    iter.skip_chunks(1);
-    assert_eq!(iter.next().unwrap().to_vec(), vec![9, 1, 2, 3]);
-    assert_eq!(iter.next().unwrap().to_vec(), vec![1, 2, 3, 4]);
-    assert_eq!(iter.next().unwrap().to_vec(), vec![2, 3, 4, 5]);
-    assert_eq!(iter.next().unwrap().to_vec(), vec![3, 4, 5, 6]);
-    iter.skip_chunks(1);
-    assert_eq!(iter.next(), None);
+    assert!(chunks.len() > 100_000 / 1024);
+    assert!(chunks.iter().all(|chunk| chunk.len() <= 1024));
+    assert_eq!(chunks.concat(), value);
 }
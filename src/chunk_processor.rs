@@ -1,21 +1,38 @@
-use std::collections::HashMap;
+use std::io::Read;
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    ad32_helper::Ad32,
     app_error::AppError,
-    chunk_iter::{ChunkIter, SkipChunk},
-    sha3_helper::Sha3,
-    types::{ChecksumStore, ChunkChecksum, DeltaStore, DiffBlock, IndexedChecksumStore},
+    cdc::CdcParams,
+    chunk_iter::ChunkIter,
+    rolling_hash::{RollingHash, RollingHashAlgorithm},
+    stream_window::StreamWindow,
+    strong_hash::StrongHashAlgorithm,
+    types::{
+        ChecksumStore, ChunkChecksum, DeltaOp, DeltaStore, HintedChunk, IndexedChecksumStore,
+        IndexedChunk,
+    },
 };
 
+// Bytes of strong hash kept for each sub-block hint, enough since a hint is only checked next to a verified match
+const HINT_HASH_LEN: usize = 8;
+
+/// Longest chunk, a window of this size is buffered while the new data is scanned
+pub const MAX_CHUNK_SIZE: usize = 1 << 30;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct InitialEmptyData;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChunkProcessor<T> {
-    chunk_size: usize,
+    pub(crate) chunk_size: usize, // Longest chunk, the fixed size of all but the last one without cdc
+    pub(crate) cdc: Option<CdcParams>,
+    pub(crate) weak_hash: RollingHashAlgorithm,
+    pub(crate) strong_hash: StrongHashAlgorithm,
+    pub(crate) strong_len: usize, // Leading bytes of the strong hash kept for each chunk
+    pub(crate) hint_len: usize, // Length of the sub-blocks hashed within each chunk, 0 without hints
+    pub(crate) unknown_tail: bool, // The last chunk may be shorter than chunk_size by an unrecorded length, as in librsync signatures
     pub data: T,
 }
 
@@ -23,53 +40,246 @@ impl ChunkProcessor<InitialEmptyData> {
     pub fn new(chunk_size: usize) -> Self {
         ChunkProcessor {
             chunk_size,
+            cdc: None,
+            weak_hash: RollingHashAlgorithm::default(),
+            strong_hash: StrongHashAlgorithm::default(),
+            strong_len: StrongHashAlgorithm::default().hasher().digest_len(),
+            hint_len: 0,
+            unknown_tail: false,
             data: InitialEmptyData,
         }
     }
+
+    // Switches to content-defined chunks, no longer than the maximum size
+    pub fn with_cdc(mut self, cdc: CdcParams) -> Self {
+        self.chunk_size = cdc.max_size;
+        self.cdc = Some(cdc);
+        self
+    }
+
+    pub fn with_weak_hash(mut self, weak_hash: RollingHashAlgorithm) -> Self {
+        self.weak_hash = weak_hash;
+        self
+    }
+
+    // Also resets the strong hash length to the full digest
+    pub fn with_strong_hash(mut self, strong_hash: StrongHashAlgorithm) -> Self {
+        self.strong_hash = strong_hash;
+        self.strong_len = strong_hash.hasher().digest_len();
+        self
+    }
+
+    pub fn with_strong_len(mut self, strong_len: usize) -> Self {
+        self.strong_len = strong_len;
+        self
+    }
+
+    pub fn with_hint_len(mut self, hint_len: usize) -> Self {
+        self.hint_len = hint_len;
+        self
+    }
 }
 
 impl<T> ChunkProcessor<T> {
+    // Same processing parameters holding other data
+    pub fn with_data<U>(&self, data: U) -> ChunkProcessor<U> {
+        ChunkProcessor {
+            chunk_size: self.chunk_size,
+            cdc: self.cdc,
+            weak_hash: self.weak_hash,
+            strong_hash: self.strong_hash,
+            strong_len: self.strong_len,
+            hint_len: self.hint_len,
+            unknown_tail: self.unknown_tail,
+            data,
+        }
+    }
+
+    // Length of the chunk starting `data`, which holds at least chunk_size bytes unless the input ends
+    pub(crate) fn cut_len(&self, data: &[u8]) -> usize {
+        match self.cdc {
+            Some(cdc) => cdc.cut(data),
+            None => data.len().min(self.chunk_size),
+        }
+    }
+
+    pub fn check_cdc_equal(&self, cdc: Option<CdcParams>) -> Result<(), AppError> {
+        if cdc == self.cdc {
+            Ok(())
+        } else {
+            Err(AppError::IncompatibleChunkSize(match self.cdc {
+                Some(params) => format!("Signature was built with {} content-defined chunks: Please use this value of the cdc parameter or omit it", params),
+                None => String::from("Signature was built with fixed size chunks: Please omit the cdc parameter"),
+            }))
+        }
+    }
+
+    // Strong hash of `data` cut to the length stored in the signature
+    pub(crate) fn strong_digest(&self, data: &[u8]) -> Vec<u8> {
+        let mut hash = self.strong_hash.hash(data);
+        hash.truncate(self.strong_len);
+        hash
+    }
+
+    // Bytes of the strong hash kept for each sub-block
+    pub(crate) fn hint_hash_len(&self) -> usize {
+        self.strong_hash.hasher().digest_len().min(HINT_HASH_LEN)
+    }
+
+    // Hashes of the sub-blocks of `chunk`, one after the other
+    pub(crate) fn hint_digests(&self, chunk: &[u8]) -> Vec<u8> {
+        match self.hint_len {
+            0 => vec![],
+            hint_len => chunk
+                .chunks(hint_len)
+                .flat_map(|block| {
+                    let mut hash = self.strong_hash.hash(block);
+                    hash.truncate(self.hint_hash_len());
+                    hash
+                })
+                .collect(),
+        }
+    }
+
+    pub fn check_weak_hash_equal(&self, weak_hash: RollingHashAlgorithm) -> Result<(), AppError> {
+        if weak_hash == self.weak_hash {
+            Ok(())
+        } else {
+            Err(AppError::IncompatibleHash(format!(
+                "Signature was built with the {} weak hash, {} can't be used with it",
+                self.weak_hash, weak_hash
+            )))
+        }
+    }
+
+    pub fn check_strong_hash_equal(
+        &self,
+        strong_hash: StrongHashAlgorithm,
+    ) -> Result<(), AppError> {
+        if strong_hash == self.strong_hash {
+            Ok(())
+        } else {
+            Err(AppError::IncompatibleHash(format!(
+                "Signature was built with the {} strong hash, {} can't be used with it",
+                self.strong_hash, strong_hash
+            )))
+        }
+    }
+
+    pub fn check_strong_len_equal(&self, strong_len: usize) -> Result<(), AppError> {
+        if strong_len == self.strong_len {
+            Ok(())
+        } else {
+            Err(AppError::IncompatibleHash(format!(
+                "Signature keeps {} bytes of each strong hash: Please use this value of the strong_len parameter or omit it",
+                self.strong_len
+            )))
+        }
+    }
+
+    pub fn check_strong_len_valid(&self) -> Result<(), AppError> {
+        let digest_len = self.strong_hash.hasher().digest_len();
+
+        if (1..=digest_len).contains(&self.strong_len) {
+            Ok(())
+        } else {
+            Err(AppError::IncompatibleHash(format!(
+                "Strong hash length must be between 1 and {} bytes for {}",
+                digest_len, self.strong_hash
+            )))
+        }
+    }
+
+    pub fn check_hint_len_equal(&self, hint_len: usize) -> Result<(), AppError> {
+        if hint_len == self.hint_len {
+            Ok(())
+        } else {
+            Err(AppError::IncompatibleChunkSize(format!(
+                "Signature keeps hints of {} bytes sub-blocks: Please use this value of the hint_len parameter or omit it",
+                self.hint_len
+            )))
+        }
+    }
+
+    pub fn check_hint_len_valid(&self) -> Result<(), AppError> {
+        if self.hint_len < self.chunk_size {
+            Ok(())
+        } else {
+            Err(AppError::IncompatibleChunkSize(format!(
+                "Sub-block hint length must be shorter than the {} bytes chunks",
+                self.chunk_size
+            )))
+        }
+    }
+
     pub fn check_chunk_size_equal(&self, chunk_size: usize) -> Result<(), AppError> {
         if chunk_size == self.chunk_size {
             Ok(())
         } else {
             Err(AppError::IncompatibleChunkSize(
-                format!("Current chunk size differes from the one for which signature was built: Please use {} value of the chunk_size parameter", self.chunk_size)
+                format!("Current chunk size differes from the one for which signature was built: Please use {} value of the chunk_size parameter or omit it", self.chunk_size)
                     .to_string(),
             ))
         }
     }
 
-    pub fn check_processing_data_size(&self, data_size: usize) -> Result<(), AppError> {
-        if data_size >= self.chunk_size * 2 {
+    pub fn check_chunk_size_valid(&self) -> Result<(), AppError> {
+        if (1..=MAX_CHUNK_SIZE).contains(&self.chunk_size) {
             Ok(())
         } else {
-            Err(AppError::IncompatibleDataSize(format!("Too small chunk of data for processing: Please provide the file which has at list 2 * {} bytes", self.chunk_size).to_string()))
+            Err(AppError::IncompatibleChunkSize(format!(
+                "Chunk size must be between 1 and {} bytes",
+                MAX_CHUNK_SIZE
+            )))
         }
     }
 }
 
 pub trait ChecksumProducer {
-    fn produce_checksum(&self, data: Vec<u8>) -> Result<ChunkProcessor<ChecksumStore>, AppError>;
+    fn produce_checksum(&self, data: &[u8]) -> Result<ChunkProcessor<ChecksumStore>, AppError>;
 }
 
 impl ChecksumProducer for ChunkProcessor<InitialEmptyData> {
-    fn produce_checksum(&self, data: Vec<u8>) -> Result<ChunkProcessor<ChecksumStore>, AppError> {
-        self.check_processing_data_size(data.len())?;
+    fn produce_checksum(&self, data: &[u8]) -> Result<ChunkProcessor<ChecksumStore>, AppError> {
+        self.check_chunk_size_valid()?;
+        self.check_strong_len_valid()?;
+        self.check_hint_len_valid()?;
 
-        let mut checksum_store = ChecksumStore::new();
+        let mut checksum_store = self.with_data(ChecksumStore::new());
+        let chunks = ChunkIter::new(data, self.chunk_size);
 
-        for chunk in ChunkIter::new(&data, self.chunk_size).by_chunk() {
-            let ad32 = chunk.ad32();
-            let hash = chunk.hash()?;
-
-            checksum_store.push(ChunkChecksum { ad32, hash });
+        match self.cdc {
+            Some(cdc) => {
+                for chunk in chunks.by_content(cdc) {
+                    checksum_store.push_chunk(chunk)?;
+                }
+            }
+            None => {
+                for chunk in chunks.by_chunk() {
+                    checksum_store.push_chunk(chunk)?;
+                }
+            }
         }
 
-        Ok(ChunkProcessor {
-            chunk_size: self.chunk_size,
-            data: checksum_store,
-        })
+        Ok(checksum_store)
+    }
+}
+
+impl ChunkProcessor<ChecksumStore> {
+    // Appends the checksums of the next basis chunk, only the last one may be shorter than chunk_size
+    pub fn push_chunk(&mut self, chunk: &[u8]) -> Result<(), AppError> {
+        let weak = self.weak_hash.hash(chunk);
+        let hash = self.strong_digest(chunk);
+        let hints = self.hint_digests(chunk);
+
+        self.data.push(ChunkChecksum {
+            weak,
+            hash,
+            len: chunk.len(),
+            hints,
+        });
+
+        Ok(())
     }
 }
 
@@ -79,100 +289,749 @@ pub trait IndexedChecksumProducer {
 
 impl IndexedChecksumProducer for ChunkProcessor<ChecksumStore> {
     fn produce_indexed_checksum(&self) -> ChunkProcessor<IndexedChecksumStore> {
-        let mut checksum_indexed_store = IndexedChecksumStore::new();
+        let mut checksum_indexed_store = IndexedChecksumStore::default();
+        let mut start = 0;
 
-        for (i, chunk_checksum) in self.data.iter().enumerate() {
-            checksum_indexed_store.insert(chunk_checksum.ad32, (chunk_checksum.hash, i));
+        for chunk_checksum in self.data.iter() {
+            checksum_indexed_store.chunks.insert(
+                chunk_checksum.weak,
+                IndexedChunk {
+                    hash: chunk_checksum.hash.clone(),
+                    start,
+                    len: chunk_checksum.len,
+                },
+            );
+
+            if chunk_checksum.len < self.chunk_size && self.cdc.is_none() {
+                checksum_indexed_store.tail_len = chunk_checksum.len;
+            }
+
+            if self.hint_len > 0 {
+                checksum_indexed_store.hinted.push(HintedChunk {
+                    start,
+                    len: chunk_checksum.len,
+                    hints: chunk_checksum.hints.clone(),
+                });
+            }
+
+            start += chunk_checksum.len;
         }
 
-        ChunkProcessor {
-            chunk_size: self.chunk_size,
-            data: checksum_indexed_store,
+        if self.unknown_tail {
+            checksum_indexed_store.unknown_tail_start = start.checked_sub(self.chunk_size);
         }
+
+        checksum_indexed_store.target_window = Some(TARGET_WINDOW_LEN);
+
+        self.with_data(checksum_indexed_store)
+    }
+}
+
+impl ChunkProcessor<IndexedChecksumStore> {
+    /// Sets the aligned windows of the new file copies from already rebuilt data must stay in,
+    /// `None`, or an empty window, only copies from the basis for formats that can't express the
+    /// other copies
+    pub fn with_target_window(mut self, target_window: Option<usize>) -> Self {
+        self.data.target_window = target_window.filter(|&window_len| window_len > 0);
+        self
     }
 }
 
 pub trait DeltaProducer {
-    fn produce_delta(&self, new_data: Vec<u8>) -> Result<ChunkProcessor<DeltaStore>, AppError>;
+    fn produce_delta(&self, new_data: &[u8]) -> Result<ChunkProcessor<DeltaStore>, AppError>;
+
+    // Emits the operations to `sink` as soon as they are known, holding only a few chunks of new data in memory
+    fn stream_delta<R, F>(&self, reader: R, sink: F) -> Result<(), AppError>
+    where
+        R: Read,
+        F: FnMut(DeltaOp) -> Result<(), AppError>;
 }
 
-impl DeltaProducer for ChunkProcessor<IndexedChecksumStore> {
-    fn produce_delta(&self, new_data: Vec<u8>) -> Result<ChunkProcessor<DeltaStore>, AppError> {
-        self.check_processing_data_size(new_data.len())?;
-        let mut diffs = DeltaStore::new();
-        let mut modified_buf = vec![];
-
-        let mut iter = ChunkIter::new(&new_data, self.chunk_size).by_byte();
-
-        while let Some(chunk) = iter.next() {
-            let ad32 = chunk.ad32();
-
-            if self.data.contains_key(&ad32) {
-                let current_hash = chunk.hash()?;
-
-                let (hash, index) = Some(
-                    self.data
-                        .get_vec(&ad32)
-                        .ok_or(AppError::IndexCorrupted)?
-                        .iter()
-                        .filter(|(hash, _)| *hash == current_hash)
-                        .collect::<Vec<&([u8; 32], usize)>>(),
-                )
-                .filter(|vec| vec.len() == 1)
-                .map(|vec| (&vec[0].0, vec[0].1))
-                .ok_or(AppError::IndexCorrupted)?;
-
-                if chunk.hash()? == *hash {
-                    diffs.insert(
-                        index.clone(),
-                        DiffBlock {
-                            start: index * self.chunk_size,
-                            offset: index * self.chunk_size + self.chunk_size,
-                            is_mising: false,
-                            buf: modified_buf.to_owned(),
-                        },
-                    );
-                    iter.skip_chunks(1);
-                    modified_buf.clear();
+// Longest literal kept in memory before it is emitted
+const LITERAL_LIMIT: usize = 64 * 1024;
+
+/// Default span of the new file searched for repeated sections, bounding the memory kept for it
+pub const TARGET_WINDOW_LEN: usize = 8 * 1024 * 1024;
+
+// Longest file rebuilt in memory, overlapping target copies let a few bytes of delta ask for any length
+const MAX_PATCHED_LEN: usize = u32::MAX as usize;
+
+// Delta under construction while the new data is scanned
+pub(crate) struct DeltaBuilder<F> {
+    sink: F,
+    modified_buf: Vec<u8>,
+    pending_copy: Option<DeltaOp>, // Last copy, held back while following matches extend it
+    pub(crate) next_start: Option<usize>, // Basis offset following the last match, preferred to keep copies contiguous
+    target: Option<TargetIndex>,
+}
+
+impl<F> DeltaBuilder<F>
+where
+    F: FnMut(DeltaOp) -> Result<(), AppError>,
+{
+    pub(crate) fn new(sink: F, target: Option<TargetIndex>) -> Self {
+        DeltaBuilder {
+            sink,
+            modified_buf: vec![],
+            pending_copy: None,
+            next_start: None,
+            target,
+        }
+    }
+
+    // Looks for the window in the new data already described, preferring the range that extends the last copy
+    pub(crate) fn find_in_target(&self, window: &[u8], weak: u32) -> Option<DeltaOp> {
+        let preferred = match self.pending_copy {
+            Some(DeltaOp::CopyTarget { start, len }) => Some(start + len),
+            _ => None,
+        };
+
+        self.target.as_ref()?.find(window, weak, preferred)
+    }
+
+    // Records the copy matched by the window, or its first byte as literal, and returns the bytes consumed
+    pub(crate) fn advance(
+        &mut self,
+        window: &[u8],
+        matched: Option<DeltaOp>,
+    ) -> Result<usize, AppError> {
+        match matched {
+            Some(copy) => {
+                let len = copy.len();
+
+                self.push_literal()?;
+                self.next_start = match copy {
+                    DeltaOp::Copy { start, len } => Some(start + len),
+                    _ => None,
+                };
+                self.push_copy(copy)?;
+                self.push_target(&window[..len]);
+
+                Ok(len)
+            }
+            None => {
+                self.push_unmatched(&window[..1])?;
+                Ok(1)
+            }
+        }
+    }
+
+    // Adds bytes found nowhere in the basis to the pending literal
+    pub(crate) fn push_unmatched(&mut self, bytes: &[u8]) -> Result<(), AppError> {
+        self.modified_buf.extend_from_slice(bytes);
+        self.next_start = None;
+        self.push_target(bytes);
+
+        if self.modified_buf.len() >= LITERAL_LIMIT {
+            self.push_literal()?;
+        }
+
+        Ok(())
+    }
+
+    // Appends an operation taken as is, merging it into the previous one where possible
+    pub(crate) fn push_op(&mut self, op: DeltaOp) -> Result<(), AppError> {
+        match op {
+            DeltaOp::Literal(bytes) => self.push_unmatched(&bytes),
+            copy => {
+                self.push_literal()?;
+                self.next_start = None;
+                self.push_copy(copy)
+            }
+        }
+    }
+
+    // Literal bytes found since the last copy
+    pub(crate) fn pending_literal(&self) -> &[u8] {
+        &self.modified_buf
+    }
+
+    // Turns the last `len` bytes of the pending literal into a copy of the basis bytes ending at `end`,
+    // for the bytes right before a match that still equal the basis
+    pub(crate) fn reclaim_literal(&mut self, end: usize, len: usize) -> Result<(), AppError> {
+        if len == 0 {
+            return Ok(());
+        }
+
+        self.modified_buf.truncate(self.modified_buf.len() - len);
+        self.push_literal()?;
+        self.push_copy(DeltaOp::Copy {
+            start: end - len,
+            len,
+        })
+    }
+
+    // Keeps the bytes the delta rebuilds so far for later windows to copy
+    fn push_target(&mut self, bytes: &[u8]) {
+        if let Some(target) = self.target.as_mut() {
+            target.push(bytes);
+        }
+    }
+
+    // Makes the chunk that was just described a target of later copies, for chunks that are not aligned blocks
+    pub(crate) fn index_target_chunk(&mut self, len: usize) {
+        if let Some(target) = self.target.as_mut() {
+            target.index_chunk(len);
+        }
+    }
+
+    // Appends a copy to the delta, merging it into the previous one when contiguous
+    fn push_copy(&mut self, copy: DeltaOp) -> Result<(), AppError> {
+        match (self.pending_copy.as_mut(), &copy) {
+            (
+                Some(DeltaOp::Copy { start, len }),
+                DeltaOp::Copy {
+                    start: next_start,
+                    len: next_len,
+                },
+            )
+            | (
+                Some(DeltaOp::CopyTarget { start, len }),
+                DeltaOp::CopyTarget {
+                    start: next_start,
+                    len: next_len,
+                },
+            ) if *start + *len == *next_start => {
+                *len += next_len;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        self.flush_copy()?;
+        self.pending_copy = Some(copy);
+
+        Ok(())
+    }
+
+    fn flush_copy(&mut self) -> Result<(), AppError> {
+        match self.pending_copy.take() {
+            Some(copy) => (self.sink)(copy),
+            None => Ok(()),
+        }
+    }
+
+    fn push_literal(&mut self) -> Result<(), AppError> {
+        if self.modified_buf.is_empty() {
+            return Ok(());
+        }
+
+        self.flush_copy()?;
+        (self.sink)(DeltaOp::Literal(std::mem::take(&mut self.modified_buf)))
+    }
+
+    pub(crate) fn finish(mut self) -> Result<(), AppError> {
+        self.push_literal()?;
+        self.flush_copy()
+    }
+}
+
+// New data already described by the delta, indexed so that sections repeated inside the new file are copied
+// from their first occurrence. Only the current aligned window of the new file is kept, copies never leave it
+pub(crate) struct TargetIndex {
+    window_len: usize,
+    block_len: Option<usize>, // Length of the aligned blocks indexed as they come, None when chunks are indexed one by one
+    weak_hash: RollingHashAlgorithm,
+    history: Vec<u8>,    // Bytes of the current window described so far
+    window_start: usize, // Offset of the current window in the new file
+    indexed: usize,      // Offset in the new file up to which aligned blocks are indexed
+    blocks: multimap::MultiMap<u32, (usize, usize)>, // Offsets and lengths of the indexed ranges by weak checksum
+}
+
+impl TargetIndex {
+    pub(crate) fn new(
+        window_len: usize,
+        block_len: Option<usize>,
+        weak_hash: RollingHashAlgorithm,
+    ) -> Self {
+        TargetIndex {
+            window_len,
+            block_len,
+            weak_hash,
+            history: vec![],
+            window_start: 0,
+            indexed: 0,
+            blocks: multimap::MultiMap::new(),
+        }
+    }
+
+    fn position(&self) -> usize {
+        self.window_start + self.history.len()
+    }
+
+    fn push(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let room = self.window_len - self.history.len();
+            let (window, rest) = bytes.split_at(bytes.len().min(room));
+            self.history.extend_from_slice(window);
+            bytes = rest;
+
+            if let Some(block_len) = self.block_len {
+                while self.indexed + block_len <= self.position() {
+                    let offset = self.indexed - self.window_start;
+                    let block = &self.history[offset..offset + block_len];
+
+                    self.blocks
+                        .insert(self.weak_hash.hash(block), (self.indexed, block_len));
+                    self.indexed += block_len;
                 }
-            } else {
-                modified_buf.push(chunk[0]);
+            }
+
+            if self.history.len() == self.window_len {
+                self.window_start += self.window_len;
+                self.indexed = self.window_start;
+                self.history.clear();
+                self.blocks.clear();
             }
         }
+    }
 
-        Ok(ChunkProcessor {
-            chunk_size: self.chunk_size,
-            data: diffs,
+    fn index_chunk(&mut self, len: usize) {
+        if len > 0 && len <= self.history.len() {
+            let offset = self.history.len() - len;
+            let chunk = &self.history[offset..];
+
+            self.blocks.insert(
+                self.weak_hash.hash(chunk),
+                (self.window_start + offset, len),
+            );
+        }
+    }
+
+    fn find(&self, window: &[u8], weak: u32, preferred: Option<usize>) -> Option<DeltaOp> {
+        // The copy has to end in the window it starts in
+        if self.history.len() + window.len() > self.window_len {
+            return None;
+        }
+
+        let candidates = self.blocks.get_vec(&weak)?;
+        let equal = |&(start, len): &(usize, usize)| {
+            let offset = start - self.window_start;
+            len == window.len() && self.history[offset..offset + len] == *window
+        };
+
+        // Ranges are indexed as the new data goes, by increasing offset, so the first equal one is
+        // the lowest. Repeated new data puts most of them under one checksum
+        let preferred = preferred.and_then(|preferred| {
+            candidates
+                .binary_search_by_key(&preferred, |(start, _)| *start)
+                .ok()
+        });
+        let start = match preferred {
+            Some(index) if equal(&candidates[index]) => Some(candidates[index].0),
+            _ => candidates
+                .iter()
+                .find(|candidate| equal(candidate))
+                .map(|(start, _)| *start),
+        };
+
+        start.map(|start| DeltaOp::CopyTarget {
+            start,
+            len: window.len(),
         })
     }
 }
 
-pub trait DeltaExtender {
-    fn extend_missed_blocks(self, chunk_checksum: &ChecksumStore) -> ChunkProcessor<DeltaStore>;
+// Weak checksum of the `len` bytes window starting at the current position of the new data
+pub(crate) struct WeakWindow {
+    len: usize,
+    roller: Box<dyn RollingHash>,
+    started: bool,
 }
 
-impl DeltaExtender for ChunkProcessor<DeltaStore> {
-    fn extend_missed_blocks(self, chunk_checksum: &ChecksumStore) -> ChunkProcessor<DeltaStore> {
-        let mut delta = self.data;
+impl WeakWindow {
+    pub(crate) fn new(len: usize, weak_hash: RollingHashAlgorithm) -> Self {
+        WeakWindow {
+            len,
+            roller: weak_hash.roller(),
+            started: false,
+        }
+    }
 
-        for i in 0..chunk_checksum.len() {
-            if !delta.contains_key(&i) {
-                delta.insert(
-                    i,
-                    DiffBlock {
-                        start: i * self.chunk_size,
-                        offset: i * self.chunk_size + self.chunk_size,
-                        is_mising: true,
-                        buf: vec![],
-                    },
-                );
+    pub(crate) fn digest(&mut self, data: &[u8]) -> Option<u32> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let window = data.get(..self.len)?;
+
+        if !self.started {
+            self.roller.init(window);
+            self.started = true;
+        }
+
+        Some(self.roller.digest())
+    }
+
+    // Slides the window one byte forward, it is restarted on the next lookup once reset
+    pub(crate) fn roll(&mut self, data: &[u8]) {
+        if self.started {
+            match data.get(self.len) {
+                Some(incoming) => self.roller.roll(data[0], *incoming),
+                None => self.started = false,
             }
         }
+    }
 
-        ChunkProcessor {
-            chunk_size: self.chunk_size,
-            data: delta,
+    pub(crate) fn reset(&mut self) {
+        self.started = false;
+    }
+}
+
+impl ChunkProcessor<IndexedChecksumStore> {
+    // Looks for a basis chunk equal to the window, preferring the one starting at `preferred` then the lowest offset
+    fn find_chunk(
+        &self,
+        window: &[u8],
+        weak: u32,
+        preferred: Option<usize>,
+    ) -> Result<Option<(usize, usize)>, AppError> {
+        let candidates = match self.data.chunks.get_vec(&weak) {
+            Some(candidates) => candidates,
+            None => return Ok(None),
+        };
+
+        let hash = self.strong_digest(window);
+
+        let matched = candidates
+            .iter()
+            .filter(|chunk| chunk.len == window.len() && chunk.hash == hash)
+            .map(|chunk| chunk.start);
+
+        let start = match preferred {
+            Some(preferred) if matched.clone().any(|start| start == preferred) => Some(preferred),
+            _ => matched.min(),
+        };
+
+        Ok(start.map(|start| (start, window.len())))
+    }
+
+    // Copy of the last chunk when its length is unknown and the rest of the new data, shorter than a
+    // chunk, has its checksums. Each try hashes the whole rest, they only happen within the last chunk
+    fn match_unknown_tail(&self, data: &[u8]) -> Option<DeltaOp> {
+        let start = self.data.unknown_tail_start?;
+
+        if data.len() >= self.chunk_size {
+            return None;
+        }
+
+        let hash = self.strong_digest(data);
+
+        self.data
+            .chunks
+            .get_vec(&self.weak_hash.hash(data))?
+            .iter()
+            .find(|chunk| chunk.start == start && chunk.hash == hash)
+            .map(|_| DeltaOp::Copy {
+                start,
+                len: data.len(),
+            })
+    }
+
+    // Content-defined chunks can't be found by rolling a window, the new data is cut the same way and each of its chunks is looked up whole
+    fn stream_content_delta<R, F>(
+        &self,
+        mut stream: StreamWindow<R>,
+        mut builder: DeltaBuilder<F>,
+    ) -> Result<(), AppError>
+    where
+        R: Read,
+        F: FnMut(DeltaOp) -> Result<(), AppError>,
+    {
+        loop {
+            stream.fill(self.chunk_size)?;
+            let data = stream.data();
+
+            if data.is_empty() {
+                break;
+            }
+
+            let chunk = &data[..self.cut_len(data)];
+            let weak = self.weak_hash.hash(chunk);
+
+            let matched = match self.find_chunk(chunk, weak, builder.next_start)? {
+                Some((start, len)) => Some(DeltaOp::Copy { start, len }),
+                None => {
+                    // A sub-block following the last copy, the chunks are cut again after it
+                    if let Some(copy) = self.match_hint_after(data, builder.next_start) {
+                        let consumed = builder.advance(data, Some(copy))?;
+                        stream.advance(consumed);
+                        continue;
+                    }
+
+                    builder.find_in_target(chunk, weak)
+                }
+            };
+
+            match matched {
+                Some(copy) => {
+                    self.reclaim_hinted(&mut builder, &copy)?;
+                    builder.advance(chunk, Some(copy))?;
+                }
+                None => builder.push_unmatched(chunk)?,
+            }
+
+            builder.index_target_chunk(chunk.len());
+            stream.advance(chunk.len());
         }
+
+        builder.finish()
+    }
+
+    // Tries the full chunk window at the start of `data` first, then the short last chunk of the basis
+    fn match_window(
+        &self,
+        data: &[u8],
+        windows: &mut [WeakWindow; 2],
+        preferred: Option<usize>,
+    ) -> Result<Option<(usize, usize)>, AppError> {
+        for window in windows.iter_mut() {
+            if let Some(weak) = window.digest(data) {
+                let matched = self.find_chunk(&data[..window.len], weak, preferred)?;
+
+                if matched.is_some() {
+                    return Ok(matched);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Copy of the sub-block following the last copy, ending at `next_start`, when the hints tell `data` starts with it
+    fn match_hint_after(&self, data: &[u8], next_start: Option<usize>) -> Option<DeltaOp> {
+        match self.hint_len {
+            0 => None,
+            _ => self.match_hint_forward(data, next_start?),
+        }
+    }
+
+    // Whether `block` has the hash of sub-block `index` of `chunk`
+    fn hint_matches(&self, chunk: &HintedChunk, index: usize, block: &[u8]) -> bool {
+        let hash_len = self.hint_hash_len();
+
+        chunk.hints.get(index * hash_len..(index + 1) * hash_len)
+            == Some(&self.hint_digests(block)[..])
+    }
+
+    // Chunk holding the basis byte at `offset`
+    fn hinted_chunk(&self, offset: usize) -> Option<&HintedChunk> {
+        let hinted = &self.data.hinted;
+        let index = hinted.partition_point(|chunk| chunk.start + chunk.len <= offset);
+
+        hinted.get(index)
+    }
+
+    // Copy of the basis sub-block starting at `start` when the hints tell `data` starts with it
+    fn match_hint_forward(&self, data: &[u8], start: usize) -> Option<DeltaOp> {
+        let chunk = self.hinted_chunk(start)?;
+        let offset = start - chunk.start;
+
+        if !offset.is_multiple_of(self.hint_len) {
+            return None;
+        }
+
+        let len = self.hint_len.min(chunk.len - offset);
+        let block = data.get(..len)?;
+
+        self.hint_matches(chunk, offset / self.hint_len, block)
+            .then_some(DeltaOp::Copy { start, len })
+    }
+
+    // Length of the end of `literal` the hints tell equals the basis sub-blocks right before `start`
+    fn hinted_len_before(&self, literal: &[u8], start: usize) -> usize {
+        let mut len = 0;
+
+        while let Some(chunk) = (start - len)
+            .checked_sub(1)
+            .and_then(|last| self.hinted_chunk(last))
+        {
+            let end = start - len - chunk.start;
+
+            if !end.is_multiple_of(self.hint_len) && end != chunk.len {
+                break;
+            }
+
+            let index = (end - 1) / self.hint_len;
+            let block_len = end - index * self.hint_len;
+
+            match (literal.len() - len).checked_sub(block_len) {
+                Some(block_start)
+                    if self.hint_matches(
+                        chunk,
+                        index,
+                        &literal[block_start..literal.len() - len],
+                    ) =>
+                {
+                    len += block_len
+                }
+                _ => break,
+            }
+        }
+
+        len
+    }
+
+    // Moves the end of the pending literal the hints tell equals the basis before `copy` into a copy
+    fn reclaim_hinted<F>(
+        &self,
+        builder: &mut DeltaBuilder<F>,
+        copy: &DeltaOp,
+    ) -> Result<(), AppError>
+    where
+        F: FnMut(DeltaOp) -> Result<(), AppError>,
+    {
+        match copy {
+            DeltaOp::Copy { start, .. } if self.hint_len > 0 => {
+                let len = self.hinted_len_before(builder.pending_literal(), *start);
+                builder.reclaim_literal(*start, len)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl DeltaProducer for ChunkProcessor<IndexedChecksumStore> {
+    fn produce_delta(&self, new_data: &[u8]) -> Result<ChunkProcessor<DeltaStore>, AppError> {
+        let mut diffs = DeltaStore::new();
+
+        self.stream_delta(new_data, |op| {
+            diffs.push(op);
+            Ok(())
+        })?;
+
+        Ok(self.with_data(diffs))
+    }
+
+    fn stream_delta<R, F>(&self, reader: R, sink: F) -> Result<(), AppError>
+    where
+        R: Read,
+        F: FnMut(DeltaOp) -> Result<(), AppError>,
+    {
+        self.check_chunk_size_valid()?;
+
+        let block_len = match self.cdc {
+            Some(_) => None,
+            None => Some(self.chunk_size),
+        };
+        let target = self
+            .data
+            .target_window
+            .map(|window_len| TargetIndex::new(window_len, block_len, self.weak_hash));
+
+        let mut builder = DeltaBuilder::new(sink, target);
+        let mut stream = StreamWindow::new(reader);
+
+        if self.cdc.is_some() {
+            return self.stream_content_delta(stream, builder);
+        }
+
+        let mut windows = [
+            WeakWindow::new(self.chunk_size, self.weak_hash),
+            WeakWindow::new(self.data.tail_len, self.weak_hash),
+        ];
+
+        loop {
+            // One byte past the window is needed to roll it forward
+            stream.fill(self.chunk_size + 1)?;
+            let data = stream.data();
+
+            if data.is_empty() {
+                break;
+            }
+
+            let matched = match self.match_window(data, &mut windows, builder.next_start)? {
+                Some((start, len)) => Some(DeltaOp::Copy { start, len }),
+                None => self
+                    .match_hint_after(data, builder.next_start)
+                    .or_else(|| self.match_unknown_tail(data))
+                    .or_else(|| {
+                        windows[0]
+                            .digest(data)
+                            .and_then(|weak| builder.find_in_target(&data[..self.chunk_size], weak))
+                    }),
+            };
+
+            if let Some(copy) = &matched {
+                self.reclaim_hinted(&mut builder, copy)?;
+            }
+
+            let found = matched.is_some();
+            let consumed = builder.advance(data, matched)?;
+
+            for window in windows.iter_mut() {
+                match found {
+                    true => window.reset(),
+                    false => window.roll(data),
+                }
+            }
+
+            stream.advance(consumed);
+        }
+
+        builder.finish()
+    }
+}
+
+pub trait PatchProducer {
+    fn produce_patch(&self, basis: &[u8]) -> Result<Vec<u8>, AppError>;
+}
+
+impl PatchProducer for ChunkProcessor<DeltaStore> {
+    fn produce_patch(&self, basis: &[u8]) -> Result<Vec<u8>, AppError> {
+        let mut patched = vec![];
+
+        for op in &self.data {
+            if patched
+                .len()
+                .checked_add(op.len())
+                .is_none_or(|len| len > MAX_PATCHED_LEN)
+            {
+                return Err(AppError::IncompatibleDelta(format!(
+                    "Delta rebuilds a file longer than {} bytes",
+                    MAX_PATCHED_LEN
+                )));
+            }
+
+            match op {
+                DeltaOp::Copy { start, len } => {
+                    let range = start
+                        .checked_add(*len)
+                        .and_then(|end| basis.get(*start..end))
+                        .ok_or_else(|| {
+                            AppError::IncompatibleDelta(format!(
+                                "Delta copies {} bytes from byte {} but the basis file has only {} bytes: Please provide the basis file the signature was built from",
+                                len,
+                                start,
+                                basis.len()
+                            ))
+                        })?;
+
+                    patched.extend_from_slice(range);
+                }
+                DeltaOp::Literal(buf) => patched.extend_from_slice(buf),
+                DeltaOp::CopyTarget { start, len } => {
+                    let end = match start.checked_add(*len) {
+                        Some(end) if *start < patched.len() => end,
+                        _ => {
+                            return Err(AppError::IncompatibleDelta(format!(
+                                "Delta copies {} bytes from byte {} of the new file but only {} bytes are rebuilt yet",
+                                len,
+                                start,
+                                patched.len()
+                            )))
+                        }
+                    };
+
+                    // Byte by byte since the range may overlap the bytes it produces
+                    for offset in *start..end {
+                        patched.push(patched[offset]);
+                    }
+                }
+            }
+        }
+
+        Ok(patched)
     }
 }
 
@@ -180,30 +1039,16 @@ impl DeltaExtender for ChunkProcessor<DeltaStore> {
 fn calculate_delta(data: Vec<u8>, new_data: Vec<u8>, chunk_size: usize) -> DeltaStore {
     let chunk_processor = ChunkProcessor::new(chunk_size);
 
-    let checksum = chunk_processor.produce_checksum(data).unwrap();
+    let checksum = chunk_processor.produce_checksum(&data).unwrap();
 
     let indexed_checksum = checksum.produce_indexed_checksum();
 
-    indexed_checksum
-        .produce_delta(new_data)
-        .unwrap()
-        .extend_missed_blocks(&checksum.data)
-        .data
+    indexed_checksum.produce_delta(&new_data).unwrap().data
 }
 
 #[cfg(test)]
-fn check_delta_match(delta: DeltaStore, expected_delta: HashMap<usize, Vec<u8>>) {
-    use std::str;
-    for (index, lit) in &expected_delta {
-        assert!(delta.contains_key(index));
-
-        let res = delta.get(index).unwrap();
-        // For better debug visibility converted to string
-        assert_eq!(
-            str::from_utf8(&res.buf).unwrap(),
-            str::from_utf8(lit).unwrap()
-        );
-    }
+fn literal(value: &str) -> DeltaOp {
+    DeltaOp::Literal(value.as_bytes().to_vec())
 }
 
 #[test]
@@ -214,27 +1059,52 @@ fn test_index_creation() {
 
     let chunk_processor = ChunkProcessor::new(4);
 
-    let checksum = chunk_processor.produce_checksum(message).unwrap();
+    let checksum = chunk_processor.produce_checksum(&message).unwrap();
     let indexed_checksum = checksum.produce_indexed_checksum().data;
 
     for (i, chunk_checksum) in checksum.data.iter().enumerate() {
-        assert!(indexed_checksum.contains_key(&chunk_checksum.ad32));
+        assert!(indexed_checksum.chunks.contains_key(&chunk_checksum.weak));
 
-        let (hash, index) = Some(
+        let chunk = Some(
             indexed_checksum
-                .get_vec(&chunk_checksum.ad32)
+                .chunks
+                .get_vec(&chunk_checksum.weak)
                 .unwrap()
                 .iter()
-                .filter(|(hash, _)| *hash == chunk_checksum.hash)
-                .collect::<Vec<&([u8; 32], usize)>>(),
+                .filter(|chunk| chunk.hash == chunk_checksum.hash)
+                .collect::<Vec<&IndexedChunk>>(),
         )
         .filter(|vec| vec.len() == 1)
-        .map(|vec| (&vec[0].0, vec[0].1))
+        .map(|vec| vec[0])
         .unwrap();
 
-        assert_eq!(index, i);
-        assert_eq!(*hash, chunk_checksum.hash);
+        assert_eq!(chunk.start, i * 4);
+        assert_eq!(chunk.len, chunk_checksum.len);
+        assert_eq!(chunk.hash, chunk_checksum.hash);
     }
+
+    // "hello world I am testing index creation" ends with a 3 bytes chunk
+    assert_eq!(indexed_checksum.tail_len, 3);
+}
+
+#[test]
+fn test_index_keeps_duplicate_chunks() {
+    let message = "abcdabcdxyzwabcd".as_bytes().to_vec();
+
+    let chunk_processor = ChunkProcessor::new(4);
+
+    let checksum = chunk_processor.produce_checksum(&message).unwrap();
+    let indexed_checksum = checksum.produce_indexed_checksum().data;
+
+    let occurrences = indexed_checksum
+        .chunks
+        .get_vec(&checksum.data[0].weak)
+        .unwrap()
+        .iter()
+        .map(|chunk| chunk.start)
+        .collect::<Vec<usize>>();
+
+    assert_eq!(occurrences, vec![0, 4, 12]);
 }
 
 #[test]
@@ -248,13 +1118,15 @@ fn test_chunk_change() {
         .as_bytes()
         .to_vec();
 
-    let expected_delta = HashMap::from([
-        (1, "i here guys h".as_bytes().to_vec()), // Match first chunk change,
-        (4, " this is a mall test chunk ".as_bytes().to_vec()), // Match chunk 4 changed
-    ]);
+    let expected_delta = vec![
+        literal("i here guys h"), // Match first chunk change
+        DeltaOp::Copy { start: 16, len: 16 },
+        literal(" this is a mall test chunk "), // Match chunk 4 changed
+        DeltaOp::Copy { start: 64, len: 22 },   // Short last chunk matched as well
+    ];
 
     let delta = calculate_delta(original, new_data, 16);
-    check_delta_match(delta, expected_delta);
+    assert_eq!(delta, expected_delta);
 }
 
 #[test]
@@ -268,12 +1140,14 @@ fn test_chunk_added() {
         .as_bytes()
         .to_vec();
 
-    let expected_delta = HashMap::from([
-        (2, "added".as_bytes().to_vec()), // Match chunks 2 changed,
-    ]);
+    let expected_delta = vec![
+        DeltaOp::Copy { start: 0, len: 32 },
+        literal("added"), // Match chunks 2 changed
+        DeltaOp::Copy { start: 32, len: 54 },
+    ];
 
     let delta = calculate_delta(original, new_data, 16);
-    check_delta_match(delta, expected_delta);
+    assert_eq!(delta, expected_delta);
 }
 
 #[test]
@@ -287,43 +1161,298 @@ fn test_chunk_removed() {
         .as_bytes()
         .to_vec();
 
+    // Chunks 0 and 3 are not copied anymore
+    let expected_delta = vec![
+        DeltaOp::Copy { start: 16, len: 32 },
+        literal(" "),
+        DeltaOp::Copy { start: 64, len: 22 },
+    ];
+
+    let delta = calculate_delta(original, new_data, 16);
+    assert_eq!(delta, expected_delta);
+}
+
+#[test]
+fn test_chunk_shifted() {
+    let original =
+        "i am here guys how are you doing this is a small test for chunk split and rolling hash"
+            .as_bytes()
+            .to_vec();
+
+    let new_data = "i am here guys   how are you doing    test for chunk split and rolling hash"
+        .as_bytes()
+        .to_vec();
+
+    let expected_delta = vec![
+        literal("i am here guys   h"), // Match 1 chunk changed
+        DeltaOp::Copy { start: 16, len: 16 },
+        literal("   "), // Match 3 chunk change
+        DeltaOp::Copy { start: 48, len: 38 },
+    ];
+
     let delta = calculate_delta(original, new_data, 16);
+    assert_eq!(delta, expected_delta);
+}
+
+#[test]
+fn test_chunk_reordered_and_duplicated() {
+    let original = "first chunk....second chunk...third chunk...."
+        .as_bytes()
+        .to_vec();
+
+    let new_data = "third chunk....first chunk....first chunk....second chunk..."
+        .as_bytes()
+        .to_vec();
 
-    let chunk_first = delta.get(&0);
-    let chunk_third = delta.get(&3);
+    let expected_delta = vec![
+        DeltaOp::Copy { start: 30, len: 15 },
+        DeltaOp::Copy { start: 0, len: 15 },
+        DeltaOp::Copy { start: 0, len: 30 },
+    ];
 
-    assert!(chunk_first.is_some());
-    assert!(chunk_third.is_some());
+    let delta = calculate_delta(original, new_data, 15);
+    assert_eq!(delta, expected_delta);
+}
 
-    let chunk_first = chunk_first.unwrap();
-    let chunk_third = chunk_third.unwrap();
+#[test]
+fn test_duplicate_chunks_in_basis() {
+    let original = [[0; 16], [1; 16], [0; 16], [0; 16]].concat();
 
-    assert_eq!(chunk_first.is_mising, true);
-    assert_eq!(chunk_third.is_mising, true);
+    // Zero chunk is taken from the lowest index unless it continues the previous copy
+    let new_data = [[0; 16], [2; 16], [1; 16], [0; 16], [0; 16]].concat();
 
-    assert_eq!(chunk_first.start, 0);
-    assert_eq!(chunk_first.offset, 16);
+    let expected_delta = vec![
+        DeltaOp::Copy { start: 0, len: 16 },
+        DeltaOp::Literal(vec![2; 16]),
+        DeltaOp::Copy { start: 16, len: 48 },
+    ];
 
-    assert_eq!(chunk_third.start, 48);
-    assert_eq!(chunk_third.offset, 64);
+    let delta = calculate_delta(original, new_data, 16);
+    assert_eq!(delta, expected_delta);
 }
 
 #[test]
-fn test_chunk_shifted() {
+fn test_short_last_chunk_matched() {
+    let original = "0123456789abcdef0123456789ABCDEFtail".as_bytes().to_vec();
+
+    // Short last chunk of the basis moved to the middle and kept at the end
+    let new_data = "tail0123456789abcdefnew0123456789ABCDEFtail"
+        .as_bytes()
+        .to_vec();
+
+    let expected_delta = vec![
+        DeltaOp::Copy { start: 32, len: 4 },
+        DeltaOp::Copy { start: 0, len: 16 },
+        literal("new"),
+        DeltaOp::Copy { start: 16, len: 20 },
+    ];
+
+    let delta = calculate_delta(original, new_data, 16);
+    assert_eq!(delta, expected_delta);
+}
+
+#[test]
+fn test_data_appended() {
     let original =
+        "i am here guys how are you doing this is a small test for chunk split and rollin"
+            .as_bytes()
+            .to_vec();
+
+    let new_data =
         "i am here guys how are you doing this is a small test for chunk split and rolling hash"
             .as_bytes()
             .to_vec();
 
-    let new_data = "i am here guys   how are you doing    test for chunk split and rolling hash"
+    let expected_delta = vec![DeltaOp::Copy { start: 0, len: 80 }, literal("g hash")];
+
+    let delta = calculate_delta(original, new_data, 16);
+    assert_eq!(delta, expected_delta);
+}
+
+#[test]
+fn test_tail_truncated() {
+    let original =
+        "i am here guys how are you doing this is a small test for chunk split and rollin"
+            .as_bytes()
+            .to_vec();
+
+    let new_data = "i am here guys how are you doing this is a small test for chunk split"
         .as_bytes()
         .to_vec();
 
-    let expected_delta = HashMap::from([
-        (1, "i am here guys   h".as_bytes().to_vec()), // Match 1 chunk changed,
-        (3, "   ".as_bytes().to_vec()),                // Match 3 chunk change
-    ]);
+    let expected_delta = vec![DeltaOp::Copy { start: 0, len: 64 }, literal("split")];
+
+    let delta = calculate_delta(original, new_data, 16);
+    assert_eq!(delta, expected_delta);
+}
+
+#[test]
+fn test_no_shared_chunks() {
+    let original =
+        "i am here guys how are you doing this is a small test for chunk split and rollin"
+            .as_bytes()
+            .to_vec();
+
+    let new_data = "nothing in this sentence can be found in the original one"
+        .as_bytes()
+        .to_vec();
+
+    let expected_delta = vec![literal(
+        "nothing in this sentence can be found in the original one",
+    )];
 
     let delta = calculate_delta(original, new_data, 16);
-    check_delta_match(delta, expected_delta);
+    assert_eq!(delta, expected_delta);
+}
+
+#[test]
+fn test_empty_basis() {
+    let new_data = "anything is new against an empty basis".as_bytes().to_vec();
+
+    let checksum = ChunkProcessor::new(16).produce_checksum(&[]).unwrap();
+    assert!(checksum.data.is_empty());
+
+    let expected_delta = vec![literal("anything is new against an empty basis")];
+
+    let delta = calculate_delta(vec![], new_data, 16);
+    assert_eq!(delta, expected_delta);
+}
+
+#[test]
+fn test_basis_smaller_than_chunk() {
+    let original = "tiny".as_bytes().to_vec();
+    let new_data = "a tiny file".as_bytes().to_vec();
+
+    let expected_delta = vec![
+        literal("a "),
+        DeltaOp::Copy { start: 0, len: 4 },
+        literal(" file"),
+    ];
+
+    let delta = calculate_delta(original, new_data, 512);
+    assert_eq!(delta, expected_delta);
+}
+
+#[test]
+fn test_new_data_smaller_than_chunk() {
+    let original =
+        "i am here guys how are you doing this is a small test for chunk split and rollin"
+            .as_bytes()
+            .to_vec();
+
+    let delta = calculate_delta(original.clone(), "small".as_bytes().to_vec(), 16);
+    assert_eq!(delta, vec![literal("small")]);
+
+    let delta = calculate_delta(original, vec![], 16);
+    assert!(delta.is_empty());
+}
+
+#[test]
+fn test_zero_chunk_size_rejected() {
+    assert!(matches!(
+        ChunkProcessor::new(0).produce_checksum(&[1, 2, 3]),
+        Err(AppError::IncompatibleChunkSize(_))
+    ));
+}
+
+#[test]
+fn test_patch_reconstructs_new_data() {
+    let original =
+        "i am here guys how are you doing this is a small test for chunk split and rollin"
+            .as_bytes()
+            .to_vec();
+
+    let new_data =
+        "i am here guys how are you doingadded this is a small test for chunk split and rollin"
+            .as_bytes()
+            .to_vec();
+
+    let delta =
+        ChunkProcessor::new(16).with_data(calculate_delta(original.clone(), new_data.clone(), 16));
+
+    assert_eq!(delta.produce_patch(&original).unwrap(), new_data);
+}
+
+#[test]
+fn test_patch_rejects_wrong_basis() {
+    let original =
+        "i am here guys how are you doing this is a small test for chunk split and rollin"
+            .as_bytes()
+            .to_vec();
+
+    let delta =
+        ChunkProcessor::new(16).with_data(calculate_delta(original.clone(), original.clone(), 16));
+
+    assert!(matches!(
+        delta.produce_patch(&original[..40]),
+        Err(AppError::IncompatibleDelta(_))
+    ));
+}
+
+#[test]
+fn test_patch_rejects_overflowing_ranges() {
+    let huge = 1 << (usize::BITS - 1);
+
+    for ops in [
+        vec![DeltaOp::Copy {
+            start: huge,
+            len: huge,
+        }],
+        vec![
+            DeltaOp::Literal(b"abc".to_vec()),
+            DeltaOp::CopyTarget {
+                start: 1,
+                len: usize::MAX,
+            },
+        ],
+        vec![DeltaOp::CopyTarget { start: 0, len: 1 }],
+    ] {
+        assert!(matches!(
+            ChunkProcessor::new(1)
+                .with_data(ops)
+                .produce_patch(b"basis"),
+            Err(AppError::IncompatibleDelta(_))
+        ));
+    }
+}
+
+#[test]
+fn test_patch_rejects_oversized_output() {
+    let delta = ChunkProcessor::new(1).with_data(vec![
+        DeltaOp::Literal(b"x".to_vec()),
+        DeltaOp::CopyTarget {
+            start: 0,
+            len: 1 << 50,
+        },
+    ]);
+
+    assert!(matches!(
+        delta.produce_patch(b"basis"),
+        Err(AppError::IncompatibleDelta(_))
+    ));
+}
+
+#[test]
+fn test_empty_target_window_copies_only_from_basis() {
+    let original = "0123456789abcdef".repeat(8).into_bytes();
+    let new_data = [
+        "ghijklmnopqrstuv".as_bytes(),
+        &original,
+        "ghijklmnopqrstuv".as_bytes(),
+    ]
+    .concat();
+
+    let delta = ChunkProcessor::new(16)
+        .produce_checksum(&original)
+        .unwrap()
+        .produce_indexed_checksum()
+        .with_target_window(Some(0))
+        .produce_delta(&new_data)
+        .unwrap();
+
+    assert!(!delta
+        .data
+        .iter()
+        .any(|op| matches!(op, DeltaOp::CopyTarget { .. })));
+    assert_eq!(delta.produce_patch(&original).unwrap(), new_data);
 }
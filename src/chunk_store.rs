@@ -0,0 +1,508 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    app_error::AppError,
+    cdc::CdcParams,
+    chunk_processor::{ChunkProcessor, InitialEmptyData},
+    file_format::{EnvelopeReader, EnvelopeWriter, FileHeader, STORE_MAGIC},
+    io_helper::IOHelper,
+    signature::Signature,
+    stream_window::StreamWindow,
+    strong_hash::StrongHashAlgorithm,
+    types::ChecksumStore,
+};
+
+#[cfg(test)]
+use crate::test_support::{test_data, TestDir};
+
+// Layout of a store directory
+const CONFIG_FILE: &str = "config";
+const REFS_FILE: &str = "refs";
+const OBJECTS_DIR: &str = "objects";
+const MANIFESTS_DIR: &str = "manifests";
+
+// Suffix of the files being written, renamed over their final name once complete
+const TEMP_SUFFIX: &str = ".tmp";
+
+// Hashes chunks are addressed by, collisions must be out of reach since a chunk is never compared
+// to the one already stored under its hash
+const STORE_HASHES: [StrongHashAlgorithm; 4] = [
+    StrongHashAlgorithm::Blake3,
+    StrongHashAlgorithm::Sha256,
+    StrongHashAlgorithm::Keccak256,
+    StrongHashAlgorithm::Blake2,
+];
+
+/// Chunks stored by [`ChunkStore::put`]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PutStats {
+    pub chunks: usize,
+    pub bytes: usize,
+    /// Chunks the store did not hold yet, the only ones written
+    pub new_chunks: usize,
+    pub new_bytes: usize,
+}
+
+/// Objects removed by [`ChunkStore::gc`]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub removed_chunks: usize,
+    pub removed_bytes: usize,
+}
+
+/// Contents of a store, see [`ChunkStore::stats`]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    pub files: usize,
+    /// Total size of the files put in the store
+    pub logical_bytes: usize,
+    pub chunks: usize,
+    /// Total size of the chunks actually stored, unreferenced ones included until collected
+    pub stored_bytes: usize,
+}
+
+impl StoreStats {
+    /// Logical size over stored size, how many times deduplication shrinks the files
+    pub fn dedup_ratio(&self) -> f64 {
+        match self.stored_bytes {
+            0 => 1.0,
+            stored_bytes => self.logical_bytes as f64 / stored_bytes as f64,
+        }
+    }
+}
+
+/// Directory holding every distinct chunk once, keyed by its strong hash, and the manifest of
+/// each file put in it: the signature listing its chunks in order
+pub struct ChunkStore {
+    root: PathBuf,
+    processor: ChunkProcessor<InitialEmptyData>,
+    refs: BTreeMap<String, u64>, // Manifest chunks pointing at each object, objects missing here are garbage
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Replaces `path` at once so that readers never see a partly written file
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), AppError> {
+    let mut temp = OsString::from(path.as_os_str());
+    temp.push(TEMP_SUFFIX);
+
+    fs::write(&temp, bytes)?;
+    fs::rename(&temp, path).map_err(AppError::from)
+}
+
+impl ChunkStore {
+    /// Creates an empty store in `root`, cutting the files put in it into `cdc` chunks
+    pub fn create<P: AsRef<Path>>(
+        root: P,
+        cdc: CdcParams,
+        strong_hash: StrongHashAlgorithm,
+    ) -> Result<Self, AppError> {
+        let root = root.as_ref().to_path_buf();
+
+        if !STORE_HASHES.contains(&strong_hash) {
+            return Err(AppError::ChunkStoreError(format!(
+                "{} is not collision resistant enough to address chunks: Please use one of {}",
+                strong_hash,
+                STORE_HASHES.map(|hash| hash.to_string()).join(", ")
+            )));
+        }
+
+        if root.join(CONFIG_FILE).exists() {
+            return Err(AppError::ChunkStoreError(format!(
+                "{} already holds a chunk store",
+                root.display()
+            )));
+        }
+
+        fs::create_dir_all(root.join(OBJECTS_DIR))?;
+        fs::create_dir_all(root.join(MANIFESTS_DIR))?;
+
+        // Full strong hashes, a truncated one could let distinct chunks share an object
+        let processor = ChunkProcessor::new(cdc.max_size)
+            .with_cdc(cdc)
+            .with_strong_hash(strong_hash);
+
+        let config = EnvelopeWriter::new(vec![], STORE_MAGIC, &FileHeader::new(&processor, 0))?;
+        write_atomic(&root.join(CONFIG_FILE), &config.finish()?)?;
+
+        let store = ChunkStore {
+            root,
+            processor,
+            refs: BTreeMap::new(),
+        };
+        store.save_refs()?;
+
+        Ok(store)
+    }
+
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self, AppError> {
+        let root = root.as_ref().to_path_buf();
+        let config = root.join(CONFIG_FILE);
+
+        if !config.exists() {
+            return Err(AppError::ChunkStoreError(format!(
+                "{} is not a chunk store: Please create it first",
+                root.display()
+            )));
+        }
+
+        let config = config.read_from_file()?;
+        let (reader, header) = EnvelopeReader::new(&config[..], STORE_MAGIC)?;
+        reader.finish()?;
+
+        let refs =
+            bincode::deserialize(&root.join(REFS_FILE).read_from_file()?).map_err(|err| {
+                AppError::CorruptedFile(format!(
+                    "Chunk store reference counts can't be decoded: {}",
+                    err
+                ))
+            })?;
+
+        Ok(ChunkStore {
+            root,
+            processor: header.processor(),
+            refs,
+        })
+    }
+
+    fn save_refs(&self) -> Result<(), AppError> {
+        let refs = bincode::serialize(&self.refs)?;
+
+        write_atomic(&self.root.join(REFS_FILE), &refs)
+    }
+
+    // Objects are spread over subdirectories named after the first byte of their hash
+    fn object_path(&self, key: &str) -> PathBuf {
+        self.root.join(OBJECTS_DIR).join(&key[..2]).join(&key[2..])
+    }
+
+    fn manifest_path(&self, name: &str) -> Result<PathBuf, AppError> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && !name.ends_with(TEMP_SUFFIX)
+            && !name.contains(['/', '\\']);
+
+        match valid {
+            true => Ok(self.root.join(MANIFESTS_DIR).join(name)),
+            false => Err(AppError::ChunkStoreError(format!(
+                "Invalid file name {:?}: it must not be empty, start with a dot, end with {} or contain a path separator",
+                name, TEMP_SUFFIX
+            ))),
+        }
+    }
+
+    /// Manifest of the file stored under `name`
+    pub fn manifest(&self, name: &str) -> Result<Signature, AppError> {
+        let path = self.manifest_path(name)?;
+
+        if !path.exists() {
+            return Err(AppError::ChunkStoreError(format!(
+                "No file named {} in the store",
+                name
+            )));
+        }
+
+        Signature::from_bytes(&path.read_from_file()?)
+    }
+
+    /// Stores the chunks of `reader` missing from the store and records them under `name`,
+    /// replacing the file previously stored under it
+    pub fn put<R: Read>(&mut self, name: &str, reader: R) -> Result<PutStats, AppError> {
+        let manifest_path = self.manifest_path(name)?;
+        let previous = match manifest_path.exists() {
+            true => Some(self.manifest(name)?),
+            false => None,
+        };
+
+        let mut checksums = self.processor.with_data(ChecksumStore::new());
+        let mut stream = StreamWindow::new(reader);
+        let mut stats = PutStats::default();
+
+        loop {
+            stream.fill(self.processor.chunk_size)?;
+            let data = stream.data();
+
+            if data.is_empty() {
+                break;
+            }
+
+            let chunk = &data[..checksums.cut_len(data)];
+            checksums.push_chunk(chunk)?;
+
+            let key = to_hex(&checksums.data[checksums.data.len() - 1].hash);
+            let object = self.object_path(&key);
+
+            if !object.exists() {
+                fs::create_dir_all(self.root.join(OBJECTS_DIR).join(&key[..2]))?;
+                write_atomic(&object, chunk)?;
+
+                stats.new_chunks += 1;
+                stats.new_bytes += chunk.len();
+            }
+
+            *self.refs.entry(key).or_default() += 1;
+            stats.chunks += 1;
+            stats.bytes += chunk.len();
+
+            stream.advance(chunk.len());
+        }
+
+        // The previous version releases its chunks only once the new one holds the shared ones
+        if let Some(previous) = previous {
+            self.release(&previous);
+        }
+
+        write_atomic(&manifest_path, &Signature { checksums }.to_bytes()?)?;
+        self.save_refs()?;
+
+        Ok(stats)
+    }
+
+    /// Writes the file stored under `name` to `writer`, checking every chunk against its hash
+    pub fn get<W: Write>(&self, name: &str, mut writer: W) -> Result<W, AppError> {
+        let manifest = self.manifest(name)?;
+
+        for checksum in &manifest.checksums.data {
+            let key = to_hex(&checksum.hash);
+            let object = self.object_path(&key);
+
+            if !object.exists() {
+                return Err(AppError::CorruptedFile(format!(
+                    "Chunk {} of {} is missing from the store",
+                    key, name
+                )));
+            }
+
+            let chunk = object.read_from_file()?;
+
+            if chunk.len() != checksum.len || self.processor.strong_digest(&chunk) != checksum.hash
+            {
+                return Err(AppError::CorruptedFile(format!(
+                    "Chunk {} of {} was altered in the store",
+                    key, name
+                )));
+            }
+
+            writer.write_all(&chunk)?;
+        }
+
+        writer.flush()?;
+
+        Ok(writer)
+    }
+
+    /// Forgets the file stored under `name`, its chunks no other file uses are removed by the next [`ChunkStore::gc`]
+    pub fn delete(&mut self, name: &str) -> Result<(), AppError> {
+        let manifest = self.manifest(name)?;
+
+        self.release(&manifest);
+        fs::remove_file(self.manifest_path(name)?)?;
+
+        self.save_refs()
+    }
+
+    fn release(&mut self, manifest: &Signature) {
+        for checksum in &manifest.checksums.data {
+            let key = to_hex(&checksum.hash);
+
+            if let Some(count) = self.refs.get_mut(&key) {
+                *count -= 1;
+
+                if *count == 0 {
+                    self.refs.remove(&key);
+                }
+            }
+        }
+    }
+
+    // Every file of the objects directory with its key, leftover temporary files included
+    fn objects(&self) -> Result<Vec<(String, PathBuf)>, AppError> {
+        let mut objects = vec![];
+
+        for dir in fs::read_dir(self.root.join(OBJECTS_DIR))? {
+            let dir = dir?;
+
+            for object in fs::read_dir(dir.path())? {
+                let object = object?;
+                let key = format!(
+                    "{}{}",
+                    dir.file_name().to_string_lossy(),
+                    object.file_name().to_string_lossy()
+                );
+
+                objects.push((key, object.path()));
+            }
+        }
+
+        Ok(objects)
+    }
+
+    /// Removes the chunks no file references anymore
+    pub fn gc(&mut self) -> Result<GcStats, AppError> {
+        let mut stats = GcStats::default();
+
+        for (key, path) in self.objects()? {
+            if !self.refs.contains_key(&key) {
+                stats.removed_bytes += fs::metadata(&path)?.len() as usize;
+                stats.removed_chunks += 1;
+
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    pub fn stats(&self) -> Result<StoreStats, AppError> {
+        let mut stats = StoreStats::default();
+
+        for manifest in fs::read_dir(self.root.join(MANIFESTS_DIR))? {
+            let name = manifest?.file_name();
+            let name = name.to_string_lossy();
+
+            // Left over by an interrupted write
+            if name.ends_with(TEMP_SUFFIX) {
+                continue;
+            }
+
+            stats.files += 1;
+            stats.logical_bytes += self.manifest(&name)?.chunks_len();
+        }
+
+        for (key, path) in self.objects()? {
+            if key.ends_with(TEMP_SUFFIX) {
+                continue;
+            }
+
+            stats.chunks += 1;
+            stats.stored_bytes += fs::metadata(path)?.len() as usize;
+        }
+
+        Ok(stats)
+    }
+}
+
+#[test]
+fn test_put_stores_shared_chunks_once() {
+    let dir = TestDir::new("put");
+    let cdc = CdcParams::from_avg(1024).unwrap();
+    let mut store = ChunkStore::create(&dir.0, cdc, StrongHashAlgorithm::Blake3).unwrap();
+
+    let first = test_data(200_000, 0);
+    let second = [&first[..100_000], "edited".as_bytes(), &first[100_000..]].concat();
+
+    let stats = store.put("first", &first[..]).unwrap();
+    assert_eq!(stats.new_chunks, stats.chunks);
+    assert_eq!(stats.bytes, first.len());
+
+    // Only the chunks around the edit are new, and nothing at all for a copy
+    let stats = store.put("second", &second[..]).unwrap();
+    assert!(stats.new_bytes < 3 * 4096, "{:?}", stats);
+    assert_eq!(store.put("copy", &first[..]).unwrap().new_chunks, 0);
+
+    let store = ChunkStore::open(&dir.0).unwrap();
+    assert_eq!(store.get("first", vec![]).unwrap(), first);
+    assert_eq!(store.get("second", vec![]).unwrap(), second);
+
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.files, 3);
+    assert_eq!(stats.logical_bytes, 3 * first.len() + 6);
+    assert!(stats.dedup_ratio() > 2.8, "{:?}", stats);
+
+    assert!(matches!(
+        ChunkStore::create(&dir.0, cdc, StrongHashAlgorithm::Blake3),
+        Err(AppError::ChunkStoreError(_))
+    ));
+    assert!(matches!(
+        store.get("../config", vec![]),
+        Err(AppError::ChunkStoreError(_))
+    ));
+
+    // Interrupted writes don't count
+    fs::write(dir.0.join(MANIFESTS_DIR).join("x.tmp"), b"partial").unwrap();
+    assert_eq!(store.stats().unwrap(), stats);
+}
+
+#[test]
+fn test_create_refuses_weak_hashes() {
+    let dir = TestDir::new("weak-hash");
+    let cdc = CdcParams::from_avg(1024).unwrap();
+
+    for strong_hash in [StrongHashAlgorithm::Xxh3, StrongHashAlgorithm::Md4] {
+        assert!(matches!(
+            ChunkStore::create(&dir.0, cdc, strong_hash),
+            Err(AppError::ChunkStoreError(_))
+        ));
+    }
+
+    assert!(!dir.0.exists());
+}
+
+#[test]
+fn test_gc_removes_only_unreferenced_chunks() {
+    let dir = TestDir::new("gc");
+    let cdc = CdcParams::from_avg(1024).unwrap();
+    let mut store = ChunkStore::create(&dir.0, cdc, StrongHashAlgorithm::default()).unwrap();
+
+    let shared = test_data(50_000, 0);
+    let first = [&shared[..], &test_data(50_000, 1)].concat();
+    let second = [&shared[..], &test_data(50_000, 2)].concat();
+
+    store.put("first", &first[..]).unwrap();
+    store.put("second", &second[..]).unwrap();
+    let stored = store.stats().unwrap().stored_bytes;
+
+    // Deleted chunks stay until collected
+    store.delete("first").unwrap();
+    assert_eq!(store.stats().unwrap().stored_bytes, stored);
+
+    let collected = store.gc().unwrap();
+    assert!(collected.removed_bytes > 40_000, "{:?}", collected);
+    assert!(collected.removed_bytes < 60_000, "{:?}", collected);
+    assert_eq!(
+        store.stats().unwrap().stored_bytes,
+        stored - collected.removed_bytes
+    );
+
+    assert_eq!(store.get("second", vec![]).unwrap(), second);
+    assert!(matches!(
+        store.get("first", vec![]),
+        Err(AppError::ChunkStoreError(_))
+    ));
+
+    // Replacing a file releases its previous version
+    store.put("second", &shared[..]).unwrap();
+    store.gc().unwrap();
+    assert_eq!(store.stats().unwrap().stored_bytes, shared.len());
+
+    store.delete("second").unwrap();
+    store.gc().unwrap();
+    assert_eq!(store.stats().unwrap(), StoreStats::default());
+}
+
+#[test]
+fn test_get_detects_altered_chunks() {
+    let dir = TestDir::new("altered");
+    let cdc = CdcParams::from_avg(1024).unwrap();
+    let mut store = ChunkStore::create(&dir.0, cdc, StrongHashAlgorithm::Blake3).unwrap();
+
+    store.put("file", &test_data(20_000, 0)[..]).unwrap();
+
+    let (_, object) = store.objects().unwrap().remove(0);
+    let mut chunk = fs::read(&object).unwrap();
+    chunk[0] ^= 1;
+    fs::write(&object, chunk).unwrap();
+
+    assert!(matches!(
+        store.get("file", vec![]),
+        Err(AppError::CorruptedFile(_))
+    ));
+}
@@ -0,0 +1,127 @@
+use clap::{Parser, Subcommand};
+use rdiff::{CdcParams, FileFormat, RollingHashAlgorithm, StrongHashAlgorithm};
+
+#[derive(Debug, Subcommand)]
+pub enum SubCommand {
+    Signature {
+        /// Basis file or directory to sign, "-" reads it from the standard input
+        #[clap(parse(from_os_str))]
+        old_file: std::path::PathBuf,
+        signature_file: std::path::PathBuf,
+    },
+    Delta {
+        #[clap(parse(from_os_str))]
+        signature_file: std::path::PathBuf,
+        /// New file or directory to compare, "-" reads it from the standard input
+        #[clap(parse(from_os_str))]
+        new_file: std::path::PathBuf,
+        /// Output delta file, "-" writes it to the standard output
+        delta_file: std::path::PathBuf,
+    },
+    Patch {
+        #[clap(parse(from_os_str))]
+        basis_file: std::path::PathBuf,
+        #[clap(parse(from_os_str))]
+        delta_file: std::path::PathBuf,
+        output_file: std::path::PathBuf,
+    },
+    /// Delta between two local files straight away, matching finer blocks than a signature allows
+    /// since the basis bytes are at hand. Only --chunk-size and --weak-hash apply
+    Diff {
+        #[clap(parse(from_os_str))]
+        old_file: std::path::PathBuf,
+        /// New file to compare, "-" reads it from the standard input
+        #[clap(parse(from_os_str))]
+        new_file: std::path::PathBuf,
+        /// Output delta file, "-" writes it to the standard output
+        delta_file: std::path::PathBuf,
+    },
+    /// Single delta equivalent to consecutive deltas applied in turn, each built against the new file
+    /// of the previous one, without the intermediate files
+    Compose {
+        /// Deltas in the order they apply, at least two
+        #[clap(parse(from_os_str), required = true, min_values = 2)]
+        delta_files: Vec<std::path::PathBuf>,
+        /// Output delta file, "-" writes it to the standard output
+        #[clap(parse(from_os_str))]
+        output_file: std::path::PathBuf,
+    },
+    /// Content-addressable store keeping each distinct chunk of the files put in it once
+    Store {
+        #[clap(parse(from_os_str))]
+        store_dir: std::path::PathBuf,
+        #[clap(subcommand)]
+        cmd: StoreCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum StoreCommand {
+    /// Creates an empty store cutting files into --cdc chunks, of 8 KiB on average by default
+    Init,
+    /// Stores the chunks of a file the store is missing and records the file under a name
+    Put {
+        name: String,
+        /// File to store, "-" reads it from the standard input
+        #[clap(parse(from_os_str))]
+        file: std::path::PathBuf,
+    },
+    /// Rebuilds a stored file
+    Get {
+        name: String,
+        /// Output file, "-" writes it to the standard output
+        #[clap(parse(from_os_str))]
+        output_file: std::path::PathBuf,
+    },
+    /// Forgets a stored file, its chunks are removed by the next gc unless other files use them
+    Delete { name: String },
+    /// Removes the chunks no stored file uses
+    Gc,
+    /// Prints the stored files and chunks sizes along with the deduplication ratio
+    Stats,
+}
+
+/// Parameters of the signature, checked against the signature file when building a delta
+#[derive(clap::Args, Debug)]
+pub struct SignatureOptions {
+    /// Chunk size in bytes. Signatures pick it from the basis file size when omitted, deltas use
+    /// the one stored in the signature and a value given here must match it
+    #[clap(short, long, value_parser)]
+    pub chunk_size: Option<usize>,
+    /// Content-defined chunks instead of fixed size ones, given as AVG or MIN:AVG:MAX sizes in
+    /// bytes. Deltas use the sizes stored in the signature and a value given here must match them
+    #[clap(long, value_parser, conflicts_with = "chunk-size")]
+    pub cdc: Option<CdcParams>,
+    /// Rolling weak hash of the chunks: adler32 (default), rollsum, rabinkarp or buzhash. Deltas use
+    /// the one stored in the signature and a value given here must match it
+    #[clap(short, long, value_parser)]
+    pub weak_hash: Option<RollingHashAlgorithm>,
+    /// Strong hash of the chunks: keccak256 (default), blake3, xxh3, sha256, md4 or blake2. Deltas use the one
+    /// stored in the signature and a value given here must match it
+    #[clap(short, long, value_parser)]
+    pub strong_hash: Option<StrongHashAlgorithm>,
+    /// Bytes of each strong hash kept in the signature. Picked from the basis file size when
+    /// omitted, deltas use the one stored in the signature and a value given here must match it
+    #[clap(short = 'S', long, value_parser)]
+    pub strong_len: Option<usize>,
+    /// Length in bytes of the sub-blocks whose hashes the signature also keeps, so deltas only send
+    /// the sub-blocks that differ next to a matching chunk. Off when omitted, deltas use the one
+    /// stored in the signature and a value given here must match it
+    #[clap(short = 'H', long, value_parser)]
+    pub hint_len: Option<usize>,
+    /// Encoding of the signature and delta files read and written: native (default), librsync,
+    /// exchangeable with librsync's rdiff and defaulting to its rabinkarp and blake2 hashes, or
+    /// vcdiff (RFC 3284) for deltas along with native signatures
+    #[clap(short, long, value_parser)]
+    pub format: Option<FileFormat>,
+}
+
+/// Represenation of the arguments provided by the user
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+pub struct Args {
+    #[clap(flatten)]
+    pub options: SignatureOptions,
+    #[clap(subcommand)]
+    pub cmd: SubCommand,
+}
@@ -0,0 +1,399 @@
+use crate::{
+    app_error::AppError,
+    chunk_processor::DeltaBuilder,
+    delta::Delta,
+    types::{DeltaOp, DeltaStore},
+};
+
+#[cfg(test)]
+use crate::{
+    chunk_processor::ChunkProcessor,
+    file_format::FileHeader,
+    rolling_hash::RollingHashAlgorithm,
+    signature::Signature,
+    test_support::{literal_len, test_data},
+};
+
+// Crafted lengths can add up past what an offset holds
+fn overflowing_len() -> AppError {
+    AppError::IncompatibleDelta(String::from(
+        "Delta rebuilds a file longer than the address space",
+    ))
+}
+
+// Operations of a delta along with the offset in its new file each one starts at
+struct OpIndex<'a> {
+    ops: &'a [DeltaOp],
+    starts: Vec<usize>,
+    len: usize, // Length of the new file the delta rebuilds
+}
+
+impl<'a> OpIndex<'a> {
+    fn new(ops: &'a [DeltaOp]) -> Result<Self, AppError> {
+        let mut starts = Vec::with_capacity(ops.len());
+        let mut len = 0;
+
+        for op in ops {
+            if let DeltaOp::CopyTarget { start, .. } = op {
+                if *start >= len {
+                    return Err(AppError::IncompatibleDelta(format!(
+                        "Delta copies from byte {} of the new file but only {} bytes are rebuilt yet",
+                        start, len
+                    )));
+                }
+            }
+
+            starts.push(len);
+            len = len.checked_add(op.len()).ok_or_else(overflowing_len)?;
+        }
+
+        Ok(OpIndex { ops, starts, len })
+    }
+
+    // Operation producing the new file byte at `offset`, with the offset it starts at
+    fn op_at(&self, offset: usize) -> Result<(usize, &'a DeltaOp), AppError> {
+        if offset >= self.len {
+            return Err(AppError::IncompatibleDelta(format!(
+                "Delta copies byte {} of a {} bytes intermediate file: Please provide consecutive deltas",
+                offset, self.len
+            )));
+        }
+
+        let index = self.starts.partition_point(|start| *start <= offset) - 1;
+
+        Ok((self.starts[index], &self.ops[index]))
+    }
+}
+
+// Work left while turning a range of the intermediate file into operations over the first basis
+enum Pending {
+    Range { start: usize, len: usize },
+    Repeat { distance: usize, len: usize }, // Bytes equal to the ones `distance` bytes before them
+}
+
+// Composed delta under construction, tracking the offset in the final file the next operation writes at
+struct Composer<F> {
+    builder: DeltaBuilder<F>,
+    position: usize,
+}
+
+impl<F> Composer<F>
+where
+    F: FnMut(DeltaOp) -> Result<(), AppError>,
+{
+    fn push(&mut self, op: DeltaOp) -> Result<(), AppError> {
+        self.position = self
+            .position
+            .checked_add(op.len())
+            .ok_or_else(overflowing_len)?;
+        self.builder.push_op(op)
+    }
+
+    // Pushes the operations rebuilding the bytes `start..start + len` of the intermediate file
+    fn push_range(&mut self, index: &OpIndex, start: usize, len: usize) -> Result<(), AppError> {
+        let mut pending = vec![Pending::Range { start, len }];
+
+        while let Some(next) = pending.pop() {
+            let (start, len) = match next {
+                Pending::Range { start, len } if len > 0 => (start, len),
+                Pending::Range { .. } => continue,
+                Pending::Repeat { distance, len } => {
+                    self.push(DeltaOp::CopyTarget {
+                        start: self.position - distance,
+                        len,
+                    })?;
+                    continue;
+                }
+            };
+
+            let (op_start, op) = index.op_at(start)?;
+            let offset = start - op_start;
+            let piece = len.min(op.len() - offset);
+
+            // The rest of the range comes from the following operations, once this piece is done
+            pending.push(Pending::Range {
+                start: start + piece,
+                len: len - piece,
+            });
+
+            match op {
+                DeltaOp::Copy { start, .. } => self.push(DeltaOp::Copy {
+                    start: start + offset,
+                    len: piece,
+                })?,
+                DeltaOp::Literal(bytes) => {
+                    self.push(DeltaOp::Literal(bytes[offset..offset + piece].to_vec()))?
+                }
+                DeltaOp::CopyTarget { start: source, .. } => {
+                    // Past the distance to its source, an overlapping copy repeats the bytes it just
+                    // produced, which the final file holds as well. Its bytes have the distance as
+                    // period, so any offset maps straight into the source
+                    let distance = op_start - source;
+
+                    if piece > distance {
+                        pending.push(Pending::Repeat {
+                            distance,
+                            len: piece - distance,
+                        });
+                    }
+
+                    pending.push(Pending::Range {
+                        start: source + offset % distance,
+                        len: piece.min(distance),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Delta from the basis of `first` to the new file of `second`, whose basis is the new file of `first`
+fn compose_pair(first: &Delta, second: &Delta) -> Result<Delta, AppError> {
+    let index = OpIndex::new(first.ops())?;
+
+    if second.header.basis_len != index.len {
+        return Err(AppError::IncompatibleDelta(format!(
+            "Delta rebuilds a {} bytes file but the next one was built for a {} bytes basis file: Please provide consecutive deltas",
+            index.len, second.header.basis_len
+        )));
+    }
+
+    let mut ops = DeltaStore::new();
+    let mut composer = Composer {
+        builder: DeltaBuilder::new(
+            |op| {
+                ops.push(op);
+                Ok(())
+            },
+            None,
+        ),
+        position: 0,
+    };
+
+    for op in second.ops() {
+        match op {
+            DeltaOp::Copy { start, len } => composer.push_range(&index, *start, *len)?,
+            DeltaOp::Literal(bytes) => composer.push(DeltaOp::Literal(bytes.clone()))?,
+            DeltaOp::CopyTarget { start, len } => composer.push(DeltaOp::CopyTarget {
+                start: *start,
+                len: *len,
+            })?,
+        }
+    }
+
+    composer.builder.finish()?;
+
+    Ok(Delta {
+        diffs: first.diffs.with_data(ops),
+        header: first.header,
+    })
+}
+
+/// Merges consecutive deltas, each built against the new file of the previous one, into a single
+/// delta turning the basis file of the first one into the new file of the last one
+pub fn compose(deltas: &[Delta]) -> Result<Delta, AppError> {
+    let (first, rest) = match deltas {
+        [first, rest @ ..] if !rest.is_empty() => (first, rest),
+        _ => {
+            return Err(AppError::IncompatibleDelta(String::from(
+                "At least two deltas are needed to compose them",
+            )))
+        }
+    };
+
+    let mut composed = compose_pair(first, &rest[0])?;
+
+    for next in &rest[1..] {
+        composed = compose_pair(&composed, next)?;
+    }
+
+    Ok(composed)
+}
+
+#[cfg(test)]
+fn delta_of(basis_len: usize, ops: Vec<DeltaOp>) -> Delta {
+    Delta {
+        diffs: ChunkProcessor::new(1).with_data(ops),
+        header: FileHeader::new(&ChunkProcessor::new(1), basis_len),
+    }
+}
+
+#[test]
+fn test_compose_matches_deltas_applied_in_turn() {
+    let v0 = test_data(120_000, 10);
+    let added = test_data(5000, 11);
+    let v1 = [&v0[..40_000], &added[..], &v0[40_000..], &added[..]].concat();
+    let mut v2 = [&v1[20_000..90_000], "inserted".as_bytes(), &v1[..20_000]].concat();
+    v2[50_001] ^= 0xff;
+    let v3 = [&v2[..], &v2[1000..3000], &test_data(700, 12)].concat();
+
+    let direct = |basis: &[u8], new_data: &[u8]| {
+        let delta =
+            crate::diff(basis, new_data, vec![], 64, RollingHashAlgorithm::default()).unwrap();
+        Delta::from_bytes(&delta).unwrap()
+    };
+
+    let deltas = [
+        direct(&v0, &v1),
+        Delta::new(&Signature::new(&v1, 512).unwrap(), &v2).unwrap(),
+        direct(&v2, &v3),
+    ];
+
+    assert_eq!(compose(&deltas[..2]).unwrap().apply(&v0).unwrap(), v2);
+    assert_eq!(compose(&deltas[1..]).unwrap().apply(&v1).unwrap(), v3);
+
+    let composed = compose(&deltas).unwrap();
+    assert_eq!(composed.apply(&v0).unwrap(), v3);
+
+    // Bytes copied all along the chain stay copies of the first basis
+    assert!(literal_len(&composed) <= deltas.iter().map(literal_len).sum::<usize>());
+
+    let decoded = Delta::from_bytes(&composed.to_bytes().unwrap()).unwrap();
+    assert_eq!(decoded.apply(&v0).unwrap(), v3);
+}
+
+#[test]
+fn test_compose_remaps_overlapping_target_copies() {
+    let basis = "0123456789".as_bytes();
+    let first = delta_of(
+        basis.len(),
+        vec![
+            DeltaOp::Literal(b"ab".to_vec()),
+            DeltaOp::CopyTarget { start: 0, len: 10 },
+            DeltaOp::Copy { start: 2, len: 5 },
+        ],
+    );
+    let middle = first.apply(basis).unwrap();
+    assert_eq!(middle, b"abababababab23456");
+
+    let second = delta_of(
+        middle.len(),
+        vec![
+            DeltaOp::Copy { start: 3, len: 12 },
+            DeltaOp::Literal(b"!".to_vec()),
+            DeltaOp::CopyTarget { start: 0, len: 4 },
+        ],
+    );
+    let expected = second.apply(&middle).unwrap();
+
+    let composed = compose(&[first, second]).unwrap();
+    assert_eq!(composed.apply(basis).unwrap(), expected);
+
+    // The repeated pattern is rebuilt from its first two bytes rather than byte by byte
+    assert_eq!(
+        composed.ops(),
+        [
+            DeltaOp::Literal(b"ba".to_vec()),
+            DeltaOp::CopyTarget { start: 0, len: 7 },
+            DeltaOp::Copy { start: 2, len: 3 },
+            DeltaOp::Literal(b"!".to_vec()),
+            DeltaOp::CopyTarget { start: 0, len: 4 },
+        ]
+    );
+}
+
+#[test]
+fn test_compose_reaches_far_into_a_repeated_byte() {
+    let run_len = 1 << 40;
+    let first = delta_of(
+        4,
+        vec![
+            DeltaOp::Literal(b"x".to_vec()),
+            DeltaOp::CopyTarget {
+                start: 0,
+                len: run_len,
+            },
+        ],
+    );
+    let second = delta_of(
+        run_len + 1,
+        vec![DeltaOp::Copy {
+            start: run_len / 2,
+            len: 10,
+        }],
+    );
+
+    // The offset inside the run maps to its single source byte without walking the run
+    let composed = compose(&[first, second]).unwrap();
+    assert_eq!(
+        composed.ops(),
+        [
+            DeltaOp::Literal(b"x".to_vec()),
+            DeltaOp::CopyTarget { start: 0, len: 9 },
+        ]
+    );
+    assert_eq!(composed.apply(b"abcd").unwrap(), b"xxxxxxxxxx");
+}
+
+#[test]
+fn test_compose_rejects_overflowing_lengths() {
+    let half = usize::MAX / 2;
+    let overflowing = delta_of(
+        1,
+        vec![
+            DeltaOp::Literal(b"x".to_vec()),
+            DeltaOp::CopyTarget {
+                start: 0,
+                len: usize::MAX,
+            },
+        ],
+    );
+    let second = delta_of(1, vec![DeltaOp::Copy { start: 0, len: 1 }]);
+
+    assert!(matches!(
+        compose(&[overflowing, second]),
+        Err(AppError::IncompatibleDelta(_))
+    ));
+
+    // Each operation fits but the composed new file doesn't
+    let first = delta_of(
+        1,
+        vec![
+            DeltaOp::Literal(b"x".to_vec()),
+            DeltaOp::CopyTarget {
+                start: 0,
+                len: half,
+            },
+        ],
+    );
+    let repeated = delta_of(
+        half + 1,
+        (0..3)
+            .map(|_| DeltaOp::Copy {
+                start: 0,
+                len: half,
+            })
+            .collect(),
+    );
+
+    assert!(matches!(
+        compose(&[first, repeated]),
+        Err(AppError::IncompatibleDelta(_))
+    ));
+}
+
+#[test]
+fn test_compose_rejects_unrelated_deltas() {
+    let first = delta_of(4, vec![DeltaOp::Copy { start: 0, len: 4 }]);
+    let second = delta_of(5, vec![DeltaOp::Copy { start: 0, len: 5 }]);
+    let short = delta_of(4, vec![DeltaOp::Copy { start: 2, len: 3 }]);
+
+    assert!(matches!(
+        compose(&[first]),
+        Err(AppError::IncompatibleDelta(_))
+    ));
+
+    let first = delta_of(4, vec![DeltaOp::Copy { start: 0, len: 4 }]);
+    assert!(matches!(
+        compose(&[first, second]),
+        Err(AppError::IncompatibleDelta(_))
+    ));
+
+    let first = delta_of(4, vec![DeltaOp::Copy { start: 0, len: 4 }]);
+    assert!(matches!(
+        compose(&[first, short]),
+        Err(AppError::IncompatibleDelta(_))
+    ));
+}
@@ -1,13 +1,19 @@
-use serde::{Deserialize, Serialize};
-
-use crate::{app_error::AppError, chunk_processor::ChunkProcessor};
+use crate::{
+    app_error::AppError,
+    chunk_processor::ChunkProcessor,
+    file_format::{EnvelopeReader, FileHeader, FileKind},
+};
 
 pub trait Decoded {
-    fn decode<'a, T: Serialize + Deserialize<'a>>(&'a self) -> Result<ChunkProcessor<T>, AppError>;
+    fn decode<T: FileKind>(&self) -> Result<(FileHeader, ChunkProcessor<T>), AppError>;
 }
 
-impl Decoded for Vec<u8> {
-    fn decode<'a, T: Serialize + Deserialize<'a>>(&'a self) -> Result<ChunkProcessor<T>, AppError> {
-        bincode::deserialize::<'a, ChunkProcessor<T>>(&self[..]).map_err(AppError::from)
+impl Decoded for [u8] {
+    fn decode<T: FileKind>(&self) -> Result<(FileHeader, ChunkProcessor<T>), AppError> {
+        let (mut reader, header) = EnvelopeReader::new(self, T::MAGIC)?;
+        let data = T::read_payload(&mut reader, &header)?;
+        reader.finish()?;
+
+        Ok((header, header.processor().with_data(data)))
     }
 }
@@ -0,0 +1,329 @@
+use std::io::{Read, Write};
+
+use crate::{
+    app_error::AppError,
+    chunk_processor::{ChunkProcessor, DeltaProducer, IndexedChecksumProducer, PatchProducer},
+    file_format::{EnvelopeReader, EnvelopeWriter, FileHeader, DELTA_MAGIC},
+    signature::Signature,
+    types::{DeltaOp, DeltaStore},
+};
+
+#[cfg(test)]
+use crate::{
+    cdc::CdcParams,
+    rolling_hash::RollingHashAlgorithm,
+    signature::SignatureBuilder,
+    strong_hash::StrongHashAlgorithm,
+    test_support::{literal_len, test_data},
+};
+
+/// Ordered operations rebuilding a new file from the basis file of a signature
+#[derive(Debug)]
+pub struct Delta {
+    pub(crate) diffs: ChunkProcessor<DeltaStore>,
+    pub(crate) header: FileHeader,
+}
+
+impl Delta {
+    /// Compares `new_data` against the chunks described by `signature`
+    pub fn new(signature: &Signature, new_data: &[u8]) -> Result<Self, AppError> {
+        let diffs = signature
+            .checksums
+            .produce_indexed_checksum()
+            .produce_delta(new_data)?;
+
+        Ok(Delta {
+            diffs,
+            header: signature.header()?,
+        })
+    }
+
+    /// Same as [`Delta::new`] for new data read from `reader`
+    pub fn from_reader<R: Read>(signature: &Signature, reader: R) -> Result<Self, AppError> {
+        let mut diffs = DeltaStore::new();
+
+        signature
+            .checksums
+            .produce_indexed_checksum()
+            .stream_delta(reader, |op| {
+                diffs.push(op);
+                Ok(())
+            })?;
+
+        Ok(Delta {
+            diffs: signature.checksums.with_data(diffs),
+            header: signature.header()?,
+        })
+    }
+
+    pub fn ops(&self) -> &[DeltaOp] {
+        &self.diffs.data
+    }
+
+    /// Replays the operations over `basis` to rebuild the new file
+    pub fn apply(&self, basis: &[u8]) -> Result<Vec<u8>, AppError> {
+        if basis.len() != self.header.basis_len {
+            return Err(AppError::IncompatibleDelta(format!(
+                "Delta was built for a {} bytes basis file but the provided one has {} bytes",
+                self.header.basis_len,
+                basis.len()
+            )));
+        }
+
+        self.diffs.produce_patch(basis)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, AppError> {
+        let mut writer = DeltaWriter::with_header(vec![], &self.header)?;
+
+        for op in self.ops() {
+            writer.write_op(op)?;
+        }
+
+        writer.finish()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, AppError> {
+        let (mut reader, header) = EnvelopeReader::new(bytes, DELTA_MAGIC)?;
+        let mut diffs = DeltaStore::new();
+
+        while let Some(op) = reader.read_value::<Option<DeltaOp>>()? {
+            diffs.push(op);
+        }
+
+        reader.finish()?;
+
+        Ok(Delta {
+            diffs: header.processor().with_data(diffs),
+            header,
+        })
+    }
+}
+
+/// Encodes delta operations one at a time, so a delta never has to be held in memory
+pub struct DeltaWriter<W: Write> {
+    writer: EnvelopeWriter<W>,
+}
+
+impl<W: Write> DeltaWriter<W> {
+    /// Starts a delta against the basis file described by `signature`
+    pub fn new(writer: W, signature: &Signature) -> Result<Self, AppError> {
+        DeltaWriter::with_header(writer, &signature.header()?)
+    }
+
+    pub(crate) fn with_header(writer: W, header: &FileHeader) -> Result<Self, AppError> {
+        Ok(DeltaWriter {
+            writer: EnvelopeWriter::new(writer, DELTA_MAGIC, header)?,
+        })
+    }
+
+    pub fn write_op(&mut self, op: &DeltaOp) -> Result<(), AppError> {
+        self.writer.write_value(&Some(op))
+    }
+
+    /// Marks the end of the operations and hands the underlying writer back
+    pub fn finish(mut self) -> Result<W, AppError> {
+        self.writer.write_value(&None::<&DeltaOp>)?;
+        self.writer.finish()
+    }
+}
+
+/// Encodes the delta of the new data read from `reader` into `writer` while it is scanned,
+/// with memory use independent of the new data size
+pub fn stream_delta<R: Read, W: Write>(
+    signature: &Signature,
+    reader: R,
+    writer: W,
+) -> Result<W, AppError> {
+    let mut writer = DeltaWriter::new(writer, signature)?;
+
+    signature
+        .checksums
+        .produce_indexed_checksum()
+        .stream_delta(reader, |op| writer.write_op(&op))?;
+
+    writer.finish()
+}
+
+#[test]
+fn test_streamed_delta_matches_in_memory_delta() {
+    let basis = test_data(50_000, 27);
+
+    let new_data = [
+        &basis[1000..20_000],
+        "inserted".as_bytes(),
+        &basis[..900],
+        &vec![7; 100_000],
+        &basis[20_003..],
+    ]
+    .concat();
+
+    let signature = Signature::new(&basis, 64).unwrap();
+    let delta = Delta::new(&signature, &new_data).unwrap();
+
+    let streamed = stream_delta(&signature, &new_data[..], vec![]).unwrap();
+    assert_eq!(streamed, delta.to_bytes().unwrap());
+
+    let decoded = Delta::from_bytes(&streamed).unwrap();
+    assert_eq!(decoded.ops(), delta.ops());
+    assert_eq!(decoded.apply(&basis).unwrap(), new_data);
+}
+
+#[test]
+fn test_apply_rejects_basis_of_other_length() {
+    let basis =
+        "i am here guys how are you doing this is a small test for chunk split and rolling hash"
+            .as_bytes()
+            .to_vec();
+
+    let signature = Signature::new(&basis, 16).unwrap();
+    let delta = Delta::new(&signature, &basis[16..]).unwrap();
+
+    assert!(matches!(
+        delta.apply(&basis[..80]),
+        Err(AppError::IncompatibleDelta(_))
+    ));
+}
+
+#[test]
+fn test_delta_round_trip_with_each_strong_hash() {
+    let basis = test_data(20_000, 28);
+    let new_data = [&basis[5000..], "tail".as_bytes(), &basis[..4000]].concat();
+
+    for strong_hash in StrongHashAlgorithm::ALL {
+        let mut builder = SignatureBuilder::new(128).unwrap().strong_hash(strong_hash);
+        builder.update(&basis).unwrap();
+        let signature =
+            Signature::from_bytes(&builder.finish().unwrap().to_bytes().unwrap()).unwrap();
+        assert_eq!(signature.strong_hash(), strong_hash);
+
+        let delta =
+            Delta::from_bytes(&stream_delta(&signature, &new_data[..], vec![]).unwrap()).unwrap();
+        assert_eq!(delta.apply(&basis).unwrap(), new_data);
+        assert!(delta
+            .ops()
+            .iter()
+            .any(|op| matches!(op, DeltaOp::Copy { .. })));
+    }
+}
+
+#[test]
+fn test_signature_rejects_other_strong_hash() {
+    let signature = Signature::new("some basis data".as_bytes(), 4).unwrap();
+
+    assert!(signature
+        .check_strong_hash_equal(StrongHashAlgorithm::default())
+        .is_ok());
+    assert!(matches!(
+        signature.check_strong_hash_equal(StrongHashAlgorithm::Blake3),
+        Err(AppError::IncompatibleHash(_))
+    ));
+}
+
+#[test]
+fn test_delta_round_trip_with_each_weak_hash() {
+    let basis = test_data(20_000, 29);
+    let new_data = [&basis[7000..], "tail".as_bytes(), &basis[..3000]].concat();
+
+    for weak_hash in RollingHashAlgorithm::ALL {
+        let mut builder = SignatureBuilder::new(128).unwrap().weak_hash(weak_hash);
+        builder.update(&basis).unwrap();
+        let signature =
+            Signature::from_bytes(&builder.finish().unwrap().to_bytes().unwrap()).unwrap();
+        assert_eq!(signature.weak_hash(), weak_hash);
+
+        let delta =
+            Delta::from_bytes(&stream_delta(&signature, &new_data[..], vec![]).unwrap()).unwrap();
+        assert_eq!(delta.apply(&basis).unwrap(), new_data);
+        assert_eq!(
+            delta.ops()[1],
+            DeltaOp::Copy {
+                start: 7040,
+                len: 12_960
+            }
+        );
+    }
+
+    assert!(matches!(
+        Signature::new(&basis, 128)
+            .unwrap()
+            .check_weak_hash_equal(RollingHashAlgorithm::Buzhash),
+        Err(AppError::IncompatibleHash(_))
+    ));
+}
+
+#[test]
+fn test_content_defined_delta_round_trip() {
+    let basis = test_data(300_000, 30);
+    let new_data = [
+        "inserted at the front".as_bytes(),
+        &basis[..150_000],
+        "and in the middle".as_bytes(),
+        &basis[150_000..],
+    ]
+    .concat();
+
+    let mut builder = SignatureBuilder::new_cdc(CdcParams::from_avg(1024).unwrap()).unwrap();
+    for piece in basis.chunks(5000) {
+        builder.update(piece).unwrap();
+    }
+    let signature = builder.finish().unwrap();
+
+    let decoded = Signature::from_bytes(&signature.to_bytes().unwrap()).unwrap();
+    assert_eq!(decoded.cdc(), signature.cdc());
+    assert_eq!(decoded.to_bytes().unwrap(), signature.to_bytes().unwrap());
+
+    let delta = Delta::new(&decoded, &new_data).unwrap();
+    assert_eq!(delta.apply(&basis).unwrap(), new_data);
+
+    let streamed = stream_delta(&decoded, &new_data[..], vec![]).unwrap();
+    assert_eq!(streamed, delta.to_bytes().unwrap());
+
+    // Only the chunks around the two insertions are sent again
+    let literal_len = literal_len(&delta);
+    assert!(literal_len < 4 * 4096, "{} literal bytes", literal_len);
+
+    assert!(matches!(
+        decoded.check_cdc_equal(None),
+        Err(AppError::IncompatibleChunkSize(_))
+    ));
+}
+
+#[test]
+fn test_repeated_new_data_is_copied_from_target() {
+    let basis = test_data(100_000, 6);
+    let added = test_data(40_000, 7);
+    let new_data = [
+        &added[..],
+        &basis[..50_000],
+        &added[..],
+        &added[1000..21_000],
+    ]
+    .concat();
+
+    let fixed = Signature::new(&basis, 1024).unwrap();
+    let mut builder = SignatureBuilder::new_cdc(CdcParams::from_avg(1024).unwrap()).unwrap();
+    builder.update(&basis).unwrap();
+    let content_defined = builder.finish().unwrap();
+
+    for signature in [fixed, content_defined] {
+        let delta = Delta::new(&signature, &new_data).unwrap();
+        assert_eq!(delta.apply(&basis).unwrap(), new_data);
+
+        let streamed = stream_delta(&signature, &new_data[..], vec![]).unwrap();
+        assert_eq!(streamed, delta.to_bytes().unwrap());
+
+        // The added bytes are sent once, their repetitions copy what was already rebuilt. Content
+        // defined chunks cut across the edges of each section still go out as literal
+        let literal_len = literal_len(&delta);
+        assert!(
+            literal_len < added.len() + 8 * 1024,
+            "{} literal bytes",
+            literal_len
+        );
+        assert!(delta
+            .ops()
+            .iter()
+            .any(|op| matches!(op, DeltaOp::CopyTarget { .. })));
+    }
+}
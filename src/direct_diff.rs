@@ -0,0 +1,333 @@
+use std::io::{Read, Write};
+
+use multimap::MultiMap;
+
+use crate::{
+    app_error::AppError,
+    chunk_processor::{ChunkProcessor, DeltaBuilder, TargetIndex, WeakWindow, TARGET_WINDOW_LEN},
+    delta::DeltaWriter,
+    file_format::FileHeader,
+    rolling_hash::RollingHashAlgorithm,
+    signature::auto_chunk_size,
+    stream_window::StreamWindow,
+    types::DeltaOp,
+};
+
+#[cfg(test)]
+use crate::{
+    delta::Delta,
+    signature::Signature,
+    test_support::{literal_len, test_data},
+};
+
+// Smallest block worth indexing, shorter ones match by chance too often
+const MIN_DIFF_BLOCK_SIZE: usize = 16;
+
+/// Block size used when diffing against a basis held in memory: nothing has to be stored or sent
+/// for the blocks, so they are much finer than the ones of a signature
+pub fn auto_diff_block_size(basis_len: usize) -> usize {
+    (auto_chunk_size(basis_len) / 8).max(MIN_DIFF_BLOCK_SIZE)
+}
+
+/// Index of the blocks of a basis available in memory, candidates found by their weak checksum
+/// are checked against the basis bytes themselves instead of a strong hash
+pub struct BasisIndex<'a> {
+    basis: &'a [u8],
+    processor: ChunkProcessor<MultiMap<u32, usize>>, // Start offsets of the blocks by weak checksum
+    target_window: Option<usize>,
+}
+
+impl<'a> BasisIndex<'a> {
+    pub fn new(
+        basis: &'a [u8],
+        block_size: usize,
+        weak_hash: RollingHashAlgorithm,
+    ) -> Result<Self, AppError> {
+        let processor = ChunkProcessor::new(block_size).with_weak_hash(weak_hash);
+        processor.check_chunk_size_valid()?;
+
+        let mut blocks = MultiMap::new();
+
+        // The short last block is left out, the bytes it covers are few enough to be sent as literal
+        for (index, block) in basis.chunks_exact(block_size).enumerate() {
+            blocks.insert(weak_hash.hash(block), index * block_size);
+        }
+
+        Ok(BasisIndex {
+            basis,
+            processor: processor.with_data(blocks),
+            target_window: Some(TARGET_WINDOW_LEN),
+        })
+    }
+
+    /// Sets the aligned windows of the new file copies from already rebuilt data must stay in,
+    /// `None`, or an empty window, only copies from the basis for formats that can't express the
+    /// other copies
+    pub fn with_target_window(mut self, target_window: Option<usize>) -> Self {
+        self.target_window = target_window.filter(|&window_len| window_len > 0);
+        self
+    }
+
+    // Looks for a basis block equal to the window, preferring the one starting at `preferred` then the lowest offset
+    fn find_block(
+        &self,
+        window: &[u8],
+        weak: u32,
+        preferred: Option<usize>,
+    ) -> Option<(usize, usize)> {
+        let candidates = self.processor.data.get_vec(&weak)?;
+        let equal = |start: usize| &self.basis[start..start + window.len()] == window;
+
+        // Blocks are indexed by increasing offset, the first equal one is the lowest. A repetitive
+        // basis puts most of its blocks under one checksum, so no more of them are compared
+        let start = match preferred {
+            Some(preferred) if candidates.binary_search(&preferred).is_ok() && equal(preferred) => {
+                Some(preferred)
+            }
+            _ => candidates.iter().copied().find(|start| equal(*start)),
+        };
+
+        start.map(|start| (start, window.len()))
+    }
+
+    // Copy of the basis bytes following the last copy, ending at `next_start`, that `data` starts with
+    fn extend_forward(&self, data: &[u8], next_start: Option<usize>) -> Option<DeltaOp> {
+        let start = next_start?;
+        let len = data
+            .iter()
+            .zip(self.basis.get(start..)?)
+            .take_while(|(new, old)| new == old)
+            .count();
+
+        (len > 0).then_some(DeltaOp::Copy { start, len })
+    }
+
+    // Length of the end of `literal` equal to the basis bytes right before `start`
+    fn extend_backward(&self, literal: &[u8], start: usize) -> usize {
+        literal
+            .iter()
+            .rev()
+            .zip(self.basis[..start].iter().rev())
+            .take_while(|(new, old)| new == old)
+            .count()
+    }
+
+    /// Emits to `sink` the operations rebuilding the new data read from `reader` out of the basis
+    pub fn stream_delta<R, F>(&self, reader: R, sink: F) -> Result<(), AppError>
+    where
+        R: Read,
+        F: FnMut(DeltaOp) -> Result<(), AppError>,
+    {
+        let block_size = self.processor.chunk_size;
+        let weak_hash = self.processor.weak_hash;
+        let target = self
+            .target_window
+            .map(|window_len| TargetIndex::new(window_len, Some(block_size), weak_hash));
+
+        let mut builder = DeltaBuilder::new(sink, target);
+        let mut stream = StreamWindow::new(reader);
+        let mut window = WeakWindow::new(block_size, weak_hash);
+
+        loop {
+            // One byte past the window is needed to roll it forward
+            stream.fill(block_size + 1)?;
+            let data = stream.data();
+
+            if data.is_empty() {
+                break;
+            }
+
+            // Matches grow byte by byte past the blocks, so only the bytes that differ are sent
+            let matched = self.extend_forward(data, builder.next_start).or_else(|| {
+                window.digest(data).and_then(|weak| {
+                    let window = &data[..block_size];
+
+                    match self.find_block(window, weak, builder.next_start) {
+                        Some((start, len)) => Some(DeltaOp::Copy { start, len }),
+                        None => builder.find_in_target(window, weak),
+                    }
+                })
+            });
+
+            if let Some(DeltaOp::Copy { start, .. }) = matched {
+                let len = self.extend_backward(builder.pending_literal(), start);
+                builder.reclaim_literal(start, len)?;
+            }
+
+            let found = matched.is_some();
+            let consumed = builder.advance(data, matched)?;
+
+            match found {
+                true => window.reset(),
+                false => window.roll(data),
+            }
+
+            stream.advance(consumed);
+        }
+
+        builder.finish()
+    }
+
+    // Header of the deltas against the basis, patching only relies on the basis length
+    fn header(&self) -> FileHeader {
+        FileHeader::new(&self.processor, self.basis.len())
+    }
+}
+
+/// Encodes into `writer` the delta turning `basis` into the new data read from `reader`, without
+/// going through a signature
+pub fn diff<R: Read, W: Write>(
+    basis: &[u8],
+    reader: R,
+    writer: W,
+    block_size: usize,
+    weak_hash: RollingHashAlgorithm,
+) -> Result<W, AppError> {
+    let index = BasisIndex::new(basis, block_size, weak_hash)?;
+    let mut writer = DeltaWriter::with_header(writer, &index.header())?;
+
+    index.stream_delta(reader, |op| writer.write_op(&op))?;
+
+    writer.finish()
+}
+
+#[cfg(test)]
+fn diff_bytes(basis: &[u8], new_data: &[u8], block_size: usize) -> Delta {
+    let delta = diff(
+        basis,
+        new_data,
+        Vec::new(),
+        block_size,
+        RollingHashAlgorithm::default(),
+    )
+    .unwrap();
+
+    Delta::from_bytes(&delta).unwrap()
+}
+
+#[test]
+fn test_direct_diff_round_trip() {
+    let basis = test_data(100_000, 3);
+    let new_data = [
+        &basis[5000..60_000],
+        "inserted".as_bytes(),
+        &basis[..4000],
+        &test_data(3000, 4),
+        &basis[70_000..],
+    ]
+    .concat();
+
+    for weak_hash in RollingHashAlgorithm::ALL {
+        let delta = diff(&basis, &new_data[..], Vec::new(), 64, weak_hash).unwrap();
+        let delta = Delta::from_bytes(&delta).unwrap();
+
+        assert_eq!(delta.apply(&basis).unwrap(), new_data);
+    }
+}
+
+#[test]
+fn test_direct_diff_is_finer_than_signature_delta() {
+    let basis = test_data(200_000, 5);
+    let mut new_data = basis.clone();
+
+    // A byte changed every 10 KB ruins a whole signature chunk each time
+    for offset in (0..new_data.len()).step_by(10_000) {
+        new_data[offset] ^= 0xff;
+    }
+
+    let block_size = auto_diff_block_size(basis.len());
+    let direct = diff_bytes(&basis, &new_data, block_size);
+    assert_eq!(direct.apply(&basis).unwrap(), new_data);
+
+    let signature = Signature::new(&basis, auto_chunk_size(basis.len())).unwrap();
+    let through_signature = Delta::new(&signature, &new_data).unwrap();
+
+    assert!(
+        literal_len(&direct) * 4 < literal_len(&through_signature),
+        "{} literal bytes against {}",
+        literal_len(&direct),
+        literal_len(&through_signature)
+    );
+}
+
+#[test]
+fn test_direct_diff_rejects_zero_block_size() {
+    assert!(diff(&[], &[][..], Vec::new(), 0, RollingHashAlgorithm::default()).is_err());
+}
+
+#[test]
+fn test_direct_diff_sends_only_differing_bytes() {
+    let basis = test_data(100_000, 8);
+    let mut new_data = [
+        &basis[..70_003],
+        "xyz".as_bytes(),
+        &basis[70_003..90_007],
+        &basis[90_012..],
+    ]
+    .concat();
+    new_data[30_001] ^= 0xff;
+
+    let delta = diff_bytes(&basis, &new_data, 64);
+    assert_eq!(delta.apply(&basis).unwrap(), new_data);
+
+    // Copies reach the changed byte and the insertion from both sides, the deletion costs nothing
+    assert_eq!(
+        delta.ops(),
+        [
+            DeltaOp::Copy {
+                start: 0,
+                len: 30_001
+            },
+            DeltaOp::Literal(vec![basis[30_001] ^ 0xff]),
+            DeltaOp::Copy {
+                start: 30_002,
+                len: 40_001
+            },
+            DeltaOp::Literal(b"xyz".to_vec()),
+            DeltaOp::Copy {
+                start: 70_003,
+                len: 20_004
+            },
+            DeltaOp::Copy {
+                start: 90_012,
+                len: 9988
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_empty_target_window_copies_only_from_basis() {
+    let basis = test_data(20_000, 9);
+    let added = test_data(5000, 10);
+    let new_data = [&added[..], &basis[..], &added[..]].concat();
+
+    let index = BasisIndex::new(&basis, 64, RollingHashAlgorithm::default())
+        .unwrap()
+        .with_target_window(Some(0));
+    let mut ops = vec![];
+    index
+        .stream_delta(&new_data[..], |op| {
+            ops.push(op);
+            Ok(())
+        })
+        .unwrap();
+
+    assert!(!ops
+        .iter()
+        .any(|op| matches!(op, DeltaOp::CopyTarget { .. })));
+}
+
+#[test]
+fn test_direct_diff_of_repetitive_basis() {
+    let basis = vec![0; 1 << 20];
+    let mut new_data = basis.clone();
+
+    for offset in (0..new_data.len()).step_by(64) {
+        new_data[offset] = 1;
+    }
+
+    let delta = diff_bytes(&basis, &new_data, 16);
+    assert_eq!(delta.apply(&basis).unwrap(), new_data);
+    assert!(literal_len(&delta) <= 16_384);
+}
@@ -1,16 +1,18 @@
-use serde::{Deserialize, Serialize};
-
-use crate::app_error::AppError;
+use crate::{
+    app_error::AppError,
+    chunk_processor::ChunkProcessor,
+    file_format::{EnvelopeWriter, FileHeader, FileKind},
+};
 
 pub trait Encoded {
-    fn into_encoded(&self) -> Result<Vec<u8>, AppError>;
+    fn to_encoded(&self, header: &FileHeader) -> Result<Vec<u8>, AppError>;
 }
 
-impl<'a, T: Serialize + Deserialize<'a>> Encoded for T
-where
-    T: Serialize,
-{
-    fn into_encoded(&self) -> Result<Vec<u8>, AppError> {
-        bincode::serialize(self).map_err(AppError::from)
+impl<T: FileKind> Encoded for ChunkProcessor<T> {
+    fn to_encoded(&self, header: &FileHeader) -> Result<Vec<u8>, AppError> {
+        let mut writer = EnvelopeWriter::new(vec![], T::MAGIC, header)?;
+        self.data.write_payload(&mut writer, header)?;
+
+        writer.finish()
     }
 }
@@ -0,0 +1,560 @@
+use std::{
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    str::FromStr,
+};
+
+use adler32::RollingAdler32;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    app_error::AppError,
+    cdc::CdcParams,
+    chunk_processor::{ChunkProcessor, InitialEmptyData},
+    rolling_hash::RollingHashAlgorithm,
+    strong_hash::StrongHashAlgorithm,
+    types::{ChecksumStore, ChunkChecksum},
+};
+
+#[cfg(test)]
+use crate::{chunk_processor::MAX_CHUNK_SIZE, Delta, Signature};
+
+pub const SIGNATURE_MAGIC: [u8; 4] = *b"RDSG";
+pub const DELTA_MAGIC: [u8; 4] = *b"RDDL";
+pub const STORE_MAGIC: [u8; 4] = *b"RDST";
+pub const TREE_SIGNATURE_MAGIC: [u8; 4] = *b"RDTS";
+pub const TREE_DELTA_MAGIC: [u8; 4] = *b"RDTD";
+
+// Bumped on every incompatible change of the header or payload layout
+pub const FORMAT_VERSION: u16 = 4;
+
+const FILE_KINDS: [([u8; 4], &str); 5] = [
+    (SIGNATURE_MAGIC, "signature"),
+    (DELTA_MAGIC, "delta"),
+    (STORE_MAGIC, "chunk store configuration"),
+    (TREE_SIGNATURE_MAGIC, "tree signature"),
+    (TREE_DELTA_MAGIC, "tree delta"),
+];
+
+/// Encoding of signature and delta files: the native one, the one of librsync's `rdiff`, or
+/// VCDIFF (RFC 3284) which only defines deltas and keeps native signatures
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FileFormat {
+    #[default]
+    Native,
+    Librsync,
+    Vcdiff,
+}
+
+impl FileFormat {
+    pub const ALL: [FileFormat; 3] = [FileFormat::Native, FileFormat::Librsync, FileFormat::Vcdiff];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FileFormat::Native => "native",
+            FileFormat::Librsync => "librsync",
+            FileFormat::Vcdiff => "vcdiff",
+        }
+    }
+}
+
+impl fmt::Display for FileFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for FileFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        FileFormat::ALL
+            .into_iter()
+            .find(|format| format.name() == value)
+            .ok_or_else(|| {
+                format!(
+                    "Unknown file format {}, expected one of: {}",
+                    value,
+                    FileFormat::ALL.map(|format| format.name()).join(", ")
+                )
+            })
+    }
+}
+
+// Payload stored in a file starting with the given magic number
+pub trait FileKind: Sized {
+    const MAGIC: [u8; 4];
+
+    fn write_payload<W: Write>(
+        &self,
+        writer: &mut EnvelopeWriter<W>,
+        header: &FileHeader,
+    ) -> Result<(), AppError>;
+
+    fn read_payload<R: Read>(
+        reader: &mut EnvelopeReader<R>,
+        header: &FileHeader,
+    ) -> Result<Self, AppError>;
+}
+
+// Chunks are stored as their weak hash followed by the truncated strong hash, then their length with
+// content-defined chunks while fixed size ones follow from the header, then the hashes of their
+// sub-blocks when the signature keeps hints
+impl FileKind for ChecksumStore {
+    const MAGIC: [u8; 4] = SIGNATURE_MAGIC;
+
+    fn write_payload<W: Write>(
+        &self,
+        writer: &mut EnvelopeWriter<W>,
+        header: &FileHeader,
+    ) -> Result<(), AppError> {
+        for checksum in self {
+            if checksum.hash.len() != header.strong_len as usize {
+                return Err(AppError::IncompatibleHash(format!(
+                    "Chunk strong hash has {} bytes instead of {}",
+                    checksum.hash.len(),
+                    header.strong_len
+                )));
+            }
+
+            writer.write_all(&checksum.weak.to_le_bytes())?;
+            writer.write_all(&checksum.hash)?;
+
+            if header.cdc.is_some() {
+                writer.write_all(&(checksum.len as u32).to_le_bytes())?;
+            }
+
+            writer.write_all(&checksum.hints)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_payload<R: Read>(
+        reader: &mut EnvelopeReader<R>,
+        header: &FileHeader,
+    ) -> Result<Self, AppError> {
+        let mut checksums = ChecksumStore::new();
+        let mut remaining = header.basis_len;
+
+        while remaining > 0 {
+            let mut weak = [0; 4];
+            let mut hash = vec![0; header.strong_len as usize];
+            reader.read_bytes(&mut weak)?;
+            reader.read_bytes(&mut hash)?;
+
+            let len = match header.cdc {
+                Some(_) => {
+                    let mut len = [0; 4];
+                    reader.read_bytes(&mut len)?;
+                    u32::from_le_bytes(len) as usize
+                }
+                None => remaining.min(header.chunk_size),
+            };
+
+            if len == 0 || len > remaining.min(header.chunk_size) {
+                return Err(AppError::CorruptedFile(format!(
+                    "Signature holds a {} bytes chunk, longer than the chunk size or the rest of the basis file",
+                    len
+                )));
+            }
+
+            remaining -= len;
+
+            let hint_count = match header.hint_len {
+                0 => 0,
+                hint_len => len.div_ceil(hint_len),
+            };
+
+            // Read one sub-block at a time so a corrupted header can't size the allocation
+            let mut hints = vec![];
+            let mut hint = vec![0; header.processor().hint_hash_len()];
+            for _ in 0..hint_count {
+                reader.read_bytes(&mut hint)?;
+                hints.extend_from_slice(&hint);
+            }
+
+            checksums.push(ChunkChecksum {
+                weak: u32::from_le_bytes(weak),
+                hash,
+                len,
+                hints,
+            });
+        }
+
+        Ok(checksums)
+    }
+}
+
+fn kind_name(magic: &[u8; 4]) -> Option<&'static str> {
+    FILE_KINDS
+        .iter()
+        .find(|(kind_magic, _)| kind_magic == magic)
+        .map(|(_, name)| *name)
+}
+
+// Parameters a signature was built with, repeated in the deltas made from it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHeader {
+    pub chunk_size: usize,
+    pub cdc: Option<CdcParams>,
+    pub weak_hash: u8,
+    pub strong_hash: u8,
+    pub strong_len: u8,
+    pub hint_len: usize,
+    pub basis_len: usize,
+}
+
+impl FileHeader {
+    pub fn new<T>(processor: &ChunkProcessor<T>, basis_len: usize) -> Self {
+        FileHeader {
+            chunk_size: processor.chunk_size,
+            cdc: processor.cdc,
+            weak_hash: processor.weak_hash.id(),
+            strong_hash: processor.strong_hash.id(),
+            strong_len: processor.strong_len as u8,
+            hint_len: processor.hint_len,
+            basis_len,
+        }
+    }
+
+    // Tree files hold no chunks of their own, each of their files embeds a complete signature or delta
+    pub fn for_tree(total_len: usize) -> Self {
+        FileHeader::new(&ChunkProcessor::new(1), total_len)
+    }
+
+    // Processor with the parameters stored in the header, which must have been checked already
+    pub fn processor(&self) -> ChunkProcessor<InitialEmptyData> {
+        let processor = ChunkProcessor::new(self.chunk_size)
+            .with_weak_hash(RollingHashAlgorithm::from_id(self.weak_hash).unwrap_or_default())
+            .with_strong_hash(StrongHashAlgorithm::from_id(self.strong_hash).unwrap_or_default())
+            .with_strong_len(self.strong_len as usize)
+            .with_hint_len(self.hint_len);
+
+        match self.cdc {
+            Some(cdc) => processor.with_cdc(cdc),
+            None => processor,
+        }
+    }
+
+    fn check_supported(&self) -> Result<(), AppError> {
+        if RollingHashAlgorithm::from_id(self.weak_hash).is_none() {
+            return Err(AppError::UnsupportedFormat(format!(
+                "Unknown weak hash algorithm {}",
+                self.weak_hash
+            )));
+        }
+
+        if StrongHashAlgorithm::from_id(self.strong_hash).is_none() {
+            return Err(AppError::UnsupportedFormat(format!(
+                "Unknown strong hash algorithm {}",
+                self.strong_hash
+            )));
+        }
+
+        if let Some(cdc) = self.cdc {
+            CdcParams::new(cdc.min_size, cdc.avg_size, cdc.max_size).map_err(|_| {
+                AppError::CorruptedFile(format!(
+                    "File header holds invalid content-defined chunk sizes {}",
+                    cdc
+                ))
+            })?;
+        }
+
+        self.processor().check_chunk_size_valid().map_err(|_| {
+            AppError::CorruptedFile(format!(
+                "File header holds an invalid chunk size {}",
+                self.chunk_size
+            ))
+        })?;
+
+        self.processor().check_strong_len_valid().map_err(|_| {
+            AppError::CorruptedFile(format!(
+                "File header holds an invalid strong hash length {}",
+                self.strong_len
+            ))
+        })?;
+
+        self.processor().check_hint_len_valid().map_err(|_| {
+            AppError::CorruptedFile(format!(
+                "File header holds an invalid sub-block hint length {}",
+                self.hint_len
+            ))
+        })?;
+
+        Ok(())
+    }
+}
+
+fn truncated() -> AppError {
+    AppError::TruncatedFile(String::from(
+        "File ends before its checksum: it was probably cut while being written or copied",
+    ))
+}
+
+fn decode_error(error: bincode::ErrorKind) -> AppError {
+    match error {
+        bincode::ErrorKind::Io(err) if err.kind() == ErrorKind::UnexpectedEof => truncated(),
+        bincode::ErrorKind::Io(err) => AppError::from(err),
+        err => AppError::CorruptedFile(format!("File payload can't be decoded: {}", err)),
+    }
+}
+
+// Writes the magic number, version and header, then checksums everything written after them
+pub struct EnvelopeWriter<W: Write> {
+    writer: W,
+    checksum: RollingAdler32,
+}
+
+impl<W: Write> EnvelopeWriter<W> {
+    pub fn new(writer: W, magic: [u8; 4], header: &FileHeader) -> Result<Self, AppError> {
+        let mut envelope = EnvelopeWriter {
+            writer,
+            checksum: RollingAdler32::new(),
+        };
+
+        envelope.write_all(&magic)?;
+        envelope.write_value(&FORMAT_VERSION)?;
+        envelope.write_value(header)?;
+
+        Ok(envelope)
+    }
+
+    pub fn write_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), AppError> {
+        bincode::serialize_into(&mut *self, value).map_err(AppError::from)
+    }
+
+    // Appends the checksum of the whole file and hands the underlying writer back
+    pub fn finish(mut self) -> Result<W, AppError> {
+        self.writer.write_all(&self.checksum.hash().to_le_bytes())?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for EnvelopeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.checksum.update_buffer(&buf[..n]);
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Checks the magic number, version and header, then checksums everything read after them
+pub struct EnvelopeReader<R: Read> {
+    reader: R,
+    checksum: RollingAdler32,
+}
+
+impl<R: Read> EnvelopeReader<R> {
+    pub fn new(reader: R, magic: [u8; 4]) -> Result<(Self, FileHeader), AppError> {
+        let mut envelope = EnvelopeReader {
+            reader,
+            checksum: RollingAdler32::new(),
+        };
+
+        let mut file_magic = [0; 4];
+        envelope.read_bytes(&mut file_magic)?;
+
+        if file_magic != magic {
+            let expected = kind_name(&magic).unwrap_or("rdiff");
+
+            return Err(AppError::WrongFileKind(match kind_name(&file_magic) {
+                Some(found) => format!("Expected a {} file but got a {} file", expected, found),
+                None => format!("Expected a {} file but got an unknown file", expected),
+            }));
+        }
+
+        let version = envelope.read_value::<u16>()?;
+
+        if version != FORMAT_VERSION {
+            return Err(AppError::UnsupportedFormat(format!(
+                "File format version {} is not supported, this build reads version {}",
+                version, FORMAT_VERSION
+            )));
+        }
+
+        let header = envelope.read_value::<FileHeader>()?;
+        header.check_supported()?;
+
+        Ok((envelope, header))
+    }
+
+    pub fn read_value<T: DeserializeOwned>(&mut self) -> Result<T, AppError> {
+        bincode::deserialize_from(&mut *self).map_err(|err| decode_error(*err))
+    }
+
+    pub fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), AppError> {
+        self.read_exact(buf).map_err(|err| match err.kind() {
+            ErrorKind::UnexpectedEof => truncated(),
+            _ => AppError::from(err),
+        })
+    }
+
+    // Compares the stored checksum with the one of the data read and makes sure nothing follows it
+    pub fn finish(mut self) -> Result<(), AppError> {
+        let mut stored = [0; 4];
+        self.reader
+            .read_exact(&mut stored)
+            .map_err(|err| match err.kind() {
+                ErrorKind::UnexpectedEof => truncated(),
+                _ => AppError::from(err),
+            })?;
+
+        if u32::from_le_bytes(stored) != self.checksum.hash() {
+            return Err(AppError::CorruptedFile(String::from(
+                "File checksum mismatch: its content was altered",
+            )));
+        }
+
+        if self.reader.read(&mut [0])? != 0 {
+            return Err(AppError::CorruptedFile(String::from(
+                "Unexpected data after the end of the file",
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for EnvelopeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.checksum.update_buffer(&buf[..n]);
+
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+fn test_files() -> (Vec<u8>, Vec<u8>) {
+    let basis =
+        "i am here guys how are you doing this is a small test for chunk split and rolling hash"
+            .as_bytes()
+            .to_vec();
+
+    let signature = Signature::new(&basis, 16).unwrap();
+    let delta = Delta::new(&signature, "i am here guys".as_bytes()).unwrap();
+
+    (signature.to_bytes().unwrap(), delta.to_bytes().unwrap())
+}
+
+#[test]
+fn test_wrong_file_kind() {
+    let (signature_file, delta_file) = test_files();
+
+    assert!(matches!(
+        Signature::from_bytes(&delta_file),
+        Err(AppError::WrongFileKind(msg)) if msg == "Expected a signature file but got a delta file"
+    ));
+    assert!(matches!(
+        Delta::from_bytes(&signature_file),
+        Err(AppError::WrongFileKind(msg)) if msg == "Expected a delta file but got a signature file"
+    ));
+    assert!(matches!(
+        Signature::from_bytes("not an rdiff file".as_bytes()),
+        Err(AppError::WrongFileKind(_))
+    ));
+}
+
+#[test]
+fn test_unsupported_version() {
+    let (mut signature_file, _) = test_files();
+    signature_file[4] = FORMAT_VERSION as u8 + 1;
+
+    assert!(matches!(
+        Signature::from_bytes(&signature_file),
+        Err(AppError::UnsupportedFormat(_))
+    ));
+}
+
+#[test]
+fn test_truncated_files() {
+    let (signature_file, delta_file) = test_files();
+
+    for len in [0, 3, 10, signature_file.len() / 2, signature_file.len() - 1] {
+        assert!(matches!(
+            Signature::from_bytes(&signature_file[..len]),
+            Err(AppError::TruncatedFile(_))
+        ));
+    }
+
+    for len in [2, delta_file.len() / 2, delta_file.len() - 1] {
+        assert!(matches!(
+            Delta::from_bytes(&delta_file[..len]),
+            Err(AppError::TruncatedFile(_))
+        ));
+    }
+}
+
+#[test]
+fn test_corrupted_files() {
+    let (signature_file, _) = test_files();
+
+    let mut altered = signature_file.clone();
+    let last_hash_byte = altered.len() - 10;
+    altered[last_hash_byte] ^= 1;
+
+    assert!(matches!(
+        Signature::from_bytes(&altered),
+        Err(AppError::CorruptedFile(_))
+    ));
+
+    let extended = [signature_file, vec![0]].concat();
+
+    assert!(matches!(
+        Signature::from_bytes(&extended),
+        Err(AppError::CorruptedFile(_))
+    ));
+}
+
+#[test]
+fn test_oversized_headers() {
+    let envelope = |header: &FileHeader, payload: &[u8]| {
+        let mut writer = EnvelopeWriter::new(vec![], SIGNATURE_MAGIC, header).unwrap();
+        writer.write_all(payload).unwrap();
+        writer.finish().unwrap()
+    };
+
+    let valid = FileHeader::new(&ChunkProcessor::new(16), 100);
+
+    for header in [
+        FileHeader {
+            chunk_size: usize::MAX,
+            ..valid
+        },
+        FileHeader {
+            cdc: Some(CdcParams {
+                min_size: 0,
+                avg_size: 0,
+                max_size: usize::MAX,
+            }),
+            ..valid
+        },
+    ] {
+        assert!(matches!(
+            Signature::from_bytes(&envelope(&header, &[])),
+            Err(AppError::CorruptedFile(_))
+        ));
+    }
+
+    // Hints claimed for a huge basis are read as they come instead of being allocated up front
+    let huge_basis = FileHeader {
+        chunk_size: MAX_CHUNK_SIZE,
+        hint_len: 1,
+        basis_len: usize::MAX,
+        ..valid
+    };
+    let payload = vec![0; 4 + huge_basis.strong_len as usize + 64];
+
+    assert!(matches!(
+        Signature::from_bytes(&envelope(&huge_basis, &payload)),
+        Err(AppError::TruncatedFile(_))
+    ));
+}
@@ -1,6 +1,6 @@
 use std::{
     fs::{self, File, OpenOptions},
-    io::{Read, Write},
+    io::{self, BufWriter, Read, Write},
     path::Path,
 };
 
@@ -8,6 +8,8 @@ use crate::app_error::AppError;
 
 pub trait IOHelper {
     fn read_from_file(&self) -> Result<Vec<u8>, AppError>;
+    fn open_reader(&self) -> Result<Box<dyn Read>, AppError>;
+    fn open_writer(&self) -> Result<Box<dyn Write>, AppError>;
     fn write_to_file(&self, buf: Vec<u8>) -> Result<(), AppError>;
 }
 
@@ -29,7 +31,7 @@ where
 
         let mut f = File::open(self)?;
 
-        let mut buffer = vec![0 as u8; file_size];
+        let mut buffer = Vec::with_capacity(file_size);
 
         let n = f.read_to_end(&mut buffer)?;
 
@@ -41,8 +43,30 @@ where
         }
     }
 
+    // Streams the file contents, "-" stands for the standard input
+    fn open_reader(&self) -> Result<Box<dyn Read>, AppError> {
+        if self.as_ref() == Path::new("-") {
+            Ok(Box::new(io::stdin()))
+        } else {
+            Ok(Box::new(File::open(self)?))
+        }
+    }
+
+    // Buffered writer replacing the file contents, "-" stands for the standard output
+    fn open_writer(&self) -> Result<Box<dyn Write>, AppError> {
+        if self.as_ref() == Path::new("-") {
+            Ok(Box::new(BufWriter::new(io::stdout())))
+        } else {
+            Ok(Box::new(BufWriter::new(File::create(self)?)))
+        }
+    }
+
     fn write_to_file(&self, buf: Vec<u8>) -> Result<(), AppError> {
-        let mut file = OpenOptions::new().write(true).open(self)?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self)?;
 
         file.write_all(&buf).map_err(AppError::from)
     }
@@ -0,0 +1,80 @@
+//! Rolling hash based file diffing: a signature of the basis file is compared
+//! against the new file to produce a delta, which patches the basis into the new file.
+
+mod app_error;
+mod cdc;
+mod chunk_iter;
+mod chunk_processor;
+mod chunk_store;
+mod compose;
+mod decode;
+mod delta;
+mod direct_diff;
+mod encode;
+mod file_format;
+mod io_helper;
+mod librsync;
+mod rolling_hash;
+mod signature;
+mod stream_window;
+mod strong_hash;
+#[cfg(test)]
+mod test_support;
+mod tree;
+mod types;
+mod vcdiff;
+
+pub use app_error::AppError;
+pub use cdc::CdcParams;
+pub use chunk_processor::TARGET_WINDOW_LEN;
+pub use chunk_store::{ChunkStore, GcStats, PutStats, StoreStats};
+pub use compose::compose;
+pub use delta::{stream_delta, Delta, DeltaWriter};
+pub use direct_diff::{auto_diff_block_size, diff, BasisIndex};
+pub use file_format::FileFormat;
+pub use io_helper::IOHelper;
+pub use librsync::{
+    patch_librsync, read_librsync_delta, stream_librsync_delta, LibrsyncDeltaWriter,
+};
+pub use rolling_hash::{RollingHash, RollingHashAlgorithm};
+pub use signature::{auto_chunk_size, auto_strong_len, Signature, SignatureBuilder};
+pub use strong_hash::{StrongHash, StrongHashAlgorithm};
+pub use tree::{patch_tree, tree_delta, TreeEntry, TreeEntryKind, TreeSignature};
+pub use types::DeltaOp;
+pub use vcdiff::{patch_vcdiff, stream_vcdiff_delta, VcdiffWriter, VCDIFF_WINDOW_LEN};
+
+/// Builds the signature of the basis `data` split into `chunk_size` bytes chunks
+pub fn signature(data: &[u8], chunk_size: usize) -> Result<Signature, AppError> {
+    Signature::new(data, chunk_size)
+}
+
+/// Builds the delta turning the basis file of `signature` into `new_data`
+pub fn delta(signature: &Signature, new_data: &[u8]) -> Result<Delta, AppError> {
+    Delta::new(signature, new_data)
+}
+
+/// Applies `delta` to the `basis` data and returns the new file contents
+pub fn patch(basis: &[u8], delta: &Delta) -> Result<Vec<u8>, AppError> {
+    delta.apply(basis)
+}
+
+#[test]
+fn test_round_trip_through_encoded_files() {
+    let basis =
+        "i am here guys how are you doing this is a small test for chunk split and rolling hash"
+            .as_bytes()
+            .to_vec();
+
+    let new_data = "i am here guys how are you doingadded this is a small test for chunk split and rolling hash"
+        .as_bytes()
+        .to_vec();
+
+    let signature_file = signature(&basis, 16).unwrap().to_bytes().unwrap();
+    let signature = Signature::from_bytes(&signature_file).unwrap();
+    assert_eq!(signature.chunk_size(), 16);
+
+    let delta_file = delta(&signature, &new_data).unwrap().to_bytes().unwrap();
+    let delta = Delta::from_bytes(&delta_file).unwrap();
+
+    assert_eq!(patch(&basis, &delta).unwrap(), new_data);
+}
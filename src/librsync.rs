@@ -0,0 +1,452 @@
+use std::io::{Read, Write};
+
+use crate::{
+    app_error::AppError,
+    chunk_processor::{ChunkProcessor, DeltaProducer, IndexedChecksumProducer, PatchProducer},
+    rolling_hash::RollingHashAlgorithm,
+    signature::Signature,
+    strong_hash::StrongHashAlgorithm,
+    types::{ChunkChecksum, DeltaOp},
+};
+
+#[cfg(test)]
+use crate::{cdc::CdcParams, signature::SignatureBuilder};
+
+// Signature magics of librsync by weak and strong sums, followed by the block length and the
+// strong sum length then by the sums of each block
+const LIBRSYNC_SIGNATURE_MAGICS: [(u32, RollingHashAlgorithm, StrongHashAlgorithm); 4] = [
+    (
+        0x7273_0136,
+        RollingHashAlgorithm::Rollsum,
+        StrongHashAlgorithm::Md4,
+    ),
+    (
+        0x7273_0137,
+        RollingHashAlgorithm::Rollsum,
+        StrongHashAlgorithm::Blake2,
+    ),
+    (
+        0x7273_0146,
+        RollingHashAlgorithm::RabinKarp,
+        StrongHashAlgorithm::Md4,
+    ),
+    (
+        0x7273_0147,
+        RollingHashAlgorithm::RabinKarp,
+        StrongHashAlgorithm::Blake2,
+    ),
+];
+
+const LIBRSYNC_DELTA_MAGIC: u32 = 0x7273_0236;
+
+// Delta commands, literals up to 64 bytes carry their length in the opcode itself
+const OP_END: u8 = 0x00;
+const OP_LITERAL_N1: u8 = 0x41;
+const OP_COPY_N1_N1: u8 = 0x45;
+const OP_COPY_N8_N8: u8 = 0x54;
+const MAX_SHORT_LITERAL: usize = 64;
+
+// Byte lengths of the integers following an opcode, picked by the opcode offset
+const INT_LENS: [usize; 4] = [1, 2, 4, 8];
+
+// Smallest of the integer lengths holding `value`
+fn int_len(value: u64) -> usize {
+    INT_LENS
+        .into_iter()
+        .find(|len| *len == 8 || value < 1 << (8 * len))
+        .unwrap_or(8)
+}
+
+fn int_len_index(value: u64) -> u8 {
+    INT_LENS
+        .iter()
+        .position(|len| *len == int_len(value))
+        .unwrap_or(3) as u8
+}
+
+// Sequential reader of the big-endian fields of a librsync file
+struct FieldReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> FieldReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], AppError> {
+        if self.bytes.len() < len {
+            return Err(AppError::TruncatedFile(String::from(
+                "librsync file ends in the middle of a field",
+            )));
+        }
+
+        let (field, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+
+        Ok(field)
+    }
+
+    fn read_int(&mut self, len: usize) -> Result<u64, AppError> {
+        Ok(self
+            .take(len)?
+            .iter()
+            .fold(0, |value, byte| (value << 8) | *byte as u64))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, AppError> {
+        Ok(self.read_int(4)? as u32)
+    }
+
+    fn read_usize(&mut self, len: usize) -> Result<usize, AppError> {
+        let value = self.read_int(len)?;
+
+        usize::try_from(value).map_err(|_| {
+            AppError::UnsupportedFile(format!("librsync value {} is too large", value))
+        })
+    }
+}
+
+impl Signature {
+    /// Encodes the signature in the format of librsync, which only knows fixed size chunks summed
+    /// with rollsum or rabinkarp and md4 or blake2
+    pub fn to_librsync_bytes(&self) -> Result<Vec<u8>, AppError> {
+        let checksums = &self.checksums;
+
+        if checksums.cdc.is_some() {
+            return Err(AppError::UnsupportedFile(String::from(
+                "librsync signatures can't hold content-defined chunks",
+            )));
+        }
+
+        if checksums.hint_len > 0 {
+            return Err(AppError::UnsupportedFile(String::from(
+                "librsync signatures can't hold sub-block hints",
+            )));
+        }
+
+        let (magic, ..) = LIBRSYNC_SIGNATURE_MAGICS
+            .into_iter()
+            .find(|(_, weak_hash, strong_hash)| {
+                *weak_hash == checksums.weak_hash && *strong_hash == checksums.strong_hash
+            })
+            .ok_or_else(|| {
+                AppError::UnsupportedFile(format!(
+                    "librsync signatures are built with rollsum or rabinkarp and md4 or blake2, not {} and {}",
+                    checksums.weak_hash, checksums.strong_hash
+                ))
+            })?;
+
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&magic.to_be_bytes());
+        bytes.extend_from_slice(&(checksums.chunk_size as u32).to_be_bytes());
+        bytes.extend_from_slice(&(checksums.strong_len as u32).to_be_bytes());
+
+        for checksum in &checksums.data {
+            bytes.extend_from_slice(&checksum.weak.to_be_bytes());
+            bytes.extend_from_slice(&checksum.hash);
+        }
+
+        Ok(bytes)
+    }
+
+    /// Decodes a signature written by librsync, which doesn't record the basis length: the last
+    /// chunk may be shorter than the others and is only matched against the end of the new data
+    pub fn from_librsync_bytes(bytes: &[u8]) -> Result<Self, AppError> {
+        let mut reader = FieldReader { bytes };
+        let magic = reader.read_u32()?;
+
+        let (_, weak_hash, strong_hash) = LIBRSYNC_SIGNATURE_MAGICS
+            .into_iter()
+            .find(|(known, ..)| *known == magic)
+            .ok_or_else(|| {
+                AppError::WrongFileKind(format!(
+                    "Magic number {:#010x} is not one of a librsync signature",
+                    magic
+                ))
+            })?;
+
+        let chunk_size = reader.read_u32()? as usize;
+        let strong_len = reader.read_u32()? as usize;
+
+        let processor = ChunkProcessor::new(chunk_size)
+            .with_weak_hash(weak_hash)
+            .with_strong_hash(strong_hash)
+            .with_strong_len(strong_len);
+        processor.check_chunk_size_valid()?;
+        processor.check_strong_len_valid()?;
+
+        let mut checksums = vec![];
+
+        while !reader.bytes.is_empty() {
+            checksums.push(ChunkChecksum {
+                weak: reader.read_u32()?,
+                hash: reader.take(strong_len)?.to_vec(),
+                len: chunk_size,
+                hints: vec![],
+            });
+        }
+
+        let mut checksums = processor.with_data(checksums);
+        checksums.unknown_tail = true;
+
+        Ok(Signature { checksums })
+    }
+}
+
+/// Encodes delta operations as librsync commands one at a time
+pub struct LibrsyncDeltaWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> LibrsyncDeltaWriter<W> {
+    pub fn new(mut writer: W) -> Result<Self, AppError> {
+        writer.write_all(&LIBRSYNC_DELTA_MAGIC.to_be_bytes())?;
+
+        Ok(LibrsyncDeltaWriter { writer })
+    }
+
+    // Big-endian `value` on the given number of bytes
+    fn write_int(&mut self, value: u64, len: usize) -> Result<(), AppError> {
+        self.writer.write_all(&value.to_be_bytes()[8 - len..])?;
+        Ok(())
+    }
+
+    pub fn write_op(&mut self, op: &DeltaOp) -> Result<(), AppError> {
+        match op {
+            DeltaOp::Literal(bytes) if bytes.is_empty() => {}
+            DeltaOp::Literal(bytes) if bytes.len() <= MAX_SHORT_LITERAL => {
+                self.writer.write_all(&[bytes.len() as u8])?;
+                self.writer.write_all(bytes)?;
+            }
+            DeltaOp::Literal(bytes) => {
+                let len = bytes.len() as u64;
+                self.writer
+                    .write_all(&[OP_LITERAL_N1 + int_len_index(len)])?;
+                self.write_int(len, int_len(len))?;
+                self.writer.write_all(bytes)?;
+            }
+            DeltaOp::Copy { start, len } => {
+                let (start, len) = (*start as u64, *len as u64);
+                self.writer
+                    .write_all(&[OP_COPY_N1_N1 + 4 * int_len_index(start) + int_len_index(len)])?;
+                self.write_int(start, int_len(start))?;
+                self.write_int(len, int_len(len))?;
+            }
+            DeltaOp::CopyTarget { .. } => {
+                return Err(AppError::IncompatibleDelta(String::from(
+                    "librsync deltas can only copy from the basis file",
+                )))
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the end command and hands the underlying writer back
+    pub fn finish(mut self) -> Result<W, AppError> {
+        self.writer.write_all(&[OP_END])?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+/// Encodes into `writer` the librsync delta of the new data read from `reader`
+pub fn stream_librsync_delta<R: Read, W: Write>(
+    signature: &Signature,
+    reader: R,
+    writer: W,
+) -> Result<W, AppError> {
+    let mut writer = LibrsyncDeltaWriter::new(writer)?;
+
+    signature
+        .checksums
+        .produce_indexed_checksum()
+        .with_target_window(None)
+        .stream_delta(reader, |op| writer.write_op(&op))?;
+
+    writer.finish()
+}
+
+/// Decodes the operations of a delta written by librsync
+pub fn read_librsync_delta(bytes: &[u8]) -> Result<Vec<DeltaOp>, AppError> {
+    let mut reader = FieldReader { bytes };
+    let magic = reader.read_u32()?;
+
+    if magic != LIBRSYNC_DELTA_MAGIC {
+        return Err(AppError::WrongFileKind(format!(
+            "Magic number {:#010x} is not the one of a librsync delta",
+            magic
+        )));
+    }
+
+    let mut ops = vec![];
+
+    loop {
+        let opcode = reader.take(1)?[0];
+
+        match opcode {
+            OP_END => break,
+            1..=0x40 => ops.push(DeltaOp::Literal(reader.take(opcode as usize)?.to_vec())),
+            OP_LITERAL_N1..=0x44 => {
+                let len = reader.read_usize(INT_LENS[(opcode - OP_LITERAL_N1) as usize])?;
+                ops.push(DeltaOp::Literal(reader.take(len)?.to_vec()));
+            }
+            OP_COPY_N1_N1..=OP_COPY_N8_N8 => {
+                let index = (opcode - OP_COPY_N1_N1) as usize;
+                let start = reader.read_usize(INT_LENS[index / 4])?;
+                let len = reader.read_usize(INT_LENS[index % 4])?;
+                ops.push(DeltaOp::Copy { start, len });
+            }
+            _ => {
+                return Err(AppError::UnsupportedFile(format!(
+                    "Unknown librsync delta command {:#04x}",
+                    opcode
+                )))
+            }
+        }
+    }
+
+    if !reader.bytes.is_empty() {
+        return Err(AppError::CorruptedFile(String::from(
+            "librsync delta goes on after its end command",
+        )));
+    }
+
+    Ok(ops)
+}
+
+/// Applies a delta written by librsync to the `basis` data and returns the new file contents
+pub fn patch_librsync(basis: &[u8], delta: &[u8]) -> Result<Vec<u8>, AppError> {
+    ChunkProcessor::new(1)
+        .with_data(read_librsync_delta(delta)?)
+        .produce_patch(basis)
+}
+
+#[cfg(test)]
+const FIXTURES: [(&str, &[u8]); 4] = [
+    ("md4", include_bytes!("../tests/fixtures/librsync/md4.sig")),
+    (
+        "blake2",
+        include_bytes!("../tests/fixtures/librsync/blake2.sig"),
+    ),
+    (
+        "rk-md4",
+        include_bytes!("../tests/fixtures/librsync/rk-md4.sig"),
+    ),
+    (
+        "rk-blake2",
+        include_bytes!("../tests/fixtures/librsync/rk-blake2.sig"),
+    ),
+];
+
+#[cfg(test)]
+const BASIS: &[u8] = include_bytes!("../tests/fixtures/librsync/basis.bin");
+
+#[cfg(test)]
+const NEW: &[u8] = include_bytes!("../tests/fixtures/librsync/new.bin");
+
+#[cfg(test)]
+const DELTA: &[u8] = include_bytes!("../tests/fixtures/librsync/new.delta");
+
+#[test]
+fn test_signatures_match_librsync_fixtures() {
+    for (name, fixture) in FIXTURES {
+        let signature = Signature::from_librsync_bytes(fixture).unwrap();
+
+        // Same parameters over the basis give back the fixture byte for byte
+        let mut builder = SignatureBuilder::new(signature.chunk_size())
+            .unwrap()
+            .weak_hash(signature.weak_hash())
+            .strong_hash(signature.strong_hash())
+            .strong_len(signature.strong_len())
+            .unwrap();
+        builder.update(BASIS).unwrap();
+
+        assert_eq!(
+            builder.finish().unwrap().to_librsync_bytes().unwrap(),
+            fixture,
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn test_delta_against_librsync_signatures_round_trip() {
+    for (name, fixture) in FIXTURES {
+        let signature = Signature::from_librsync_bytes(fixture).unwrap();
+        let delta = stream_librsync_delta(&signature, NEW, Vec::new()).unwrap();
+
+        assert_eq!(patch_librsync(BASIS, &delta).unwrap(), NEW, "{}", name);
+        assert!(
+            delta.len() < NEW.len() / 10,
+            "{}: {} bytes",
+            name,
+            delta.len()
+        );
+    }
+}
+
+#[test]
+fn test_librsync_delta_fixture() {
+    let ops = read_librsync_delta(DELTA).unwrap();
+    assert_eq!(patch_librsync(BASIS, DELTA).unwrap(), NEW);
+
+    // rdiff may split its commands differently, re-encoding only has to keep the operations
+    let mut writer = LibrsyncDeltaWriter::new(Vec::new()).unwrap();
+    for op in &ops {
+        writer.write_op(op).unwrap();
+    }
+    assert_eq!(read_librsync_delta(&writer.finish().unwrap()).unwrap(), ops);
+}
+
+#[test]
+fn test_librsync_signature_matches_short_last_block() {
+    let signature = Signature::from_librsync_bytes(FIXTURES[0].1).unwrap();
+    assert_eq!(signature.basis_len(), None);
+    assert!(matches!(
+        crate::Delta::new(&signature, NEW),
+        Err(AppError::UnsupportedFile(_))
+    ));
+
+    // The basis ends with a 464 bytes block, copied once the new data reaches its end
+    let new_data = [&b"front"[..], &BASIS[60_000..]].concat();
+    let delta = stream_librsync_delta(&signature, &new_data[..], Vec::new()).unwrap();
+    let ops = read_librsync_delta(&delta).unwrap();
+
+    assert_eq!(patch_librsync(BASIS, &delta).unwrap(), new_data);
+    assert_eq!(
+        ops.last(),
+        Some(&DeltaOp::Copy {
+            start: 61_440,
+            len: 4560
+        })
+    );
+}
+
+#[test]
+fn test_librsync_rejects_bad_files() {
+    assert!(read_librsync_delta(&DELTA[..DELTA.len() - 1]).is_err());
+    assert!(read_librsync_delta(&[DELTA, &[0]].concat()).is_err());
+    assert!(read_librsync_delta(FIXTURES[0].1).is_err());
+    assert!(Signature::from_librsync_bytes(DELTA).is_err());
+    assert!(Signature::from_librsync_bytes(&FIXTURES[0].1[..30]).is_err());
+
+    // COPY_N8_N8 with a start and length adding up past the address space
+    let overflowing = [
+        &0x7273_0236_u32.to_be_bytes()[..],
+        &[0x54],
+        &(1_u64 << 63).to_be_bytes(),
+        &(1_u64 << 63).to_be_bytes(),
+        &[0],
+    ]
+    .concat();
+    assert!(matches!(
+        patch_librsync(BASIS, &overflowing),
+        Err(AppError::IncompatibleDelta(_))
+    ));
+
+    let cdc = SignatureBuilder::new_cdc(CdcParams::from_avg(1024).unwrap())
+        .unwrap()
+        .finish()
+        .unwrap();
+    assert!(cdc.to_librsync_bytes().is_err());
+}
@@ -1,59 +1,321 @@
-mod ad32_helper;
-mod app_error;
-mod chunk_iter;
-mod chunk_processor;
-mod decode;
-mod encode;
-mod io_helper;
-mod sha3_helper;
-mod types;
-
-use app_error::AppError;
-use chunk_processor::{
-    ChecksumProducer, ChunkProcessor, DeltaExtender, DeltaProducer, IndexedChecksumProducer,
-};
+mod cli;
+
 use clap::Parser;
-use decode::Decoded;
-use encode::Encoded;
-use io_helper::IOHelper;
-use std::path::Path;
-use types::{Args, ChecksumStore, SubCommand};
+use cli::{Args, SignatureOptions, StoreCommand, SubCommand};
+use rdiff::{
+    AppError, BasisIndex, CdcParams, ChunkStore, Delta, FileFormat, IOHelper, LibrsyncDeltaWriter,
+    RollingHashAlgorithm, Signature, SignatureBuilder, StrongHashAlgorithm, TreeEntryKind,
+    TreeSignature, VcdiffWriter,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+// Used when the basis size is unknown, e.g. when it is read from the standard input
+const DEFAULT_CHUNK_SIZE: usize = 512;
+
+// Average chunk size of the stores created without --cdc
+const DEFAULT_STORE_CHUNK_SIZE: usize = 8 * 1024;
+
+// Hashes of librsync's rdiff when none is given, ours otherwise
+fn default_hashes(options: &SignatureOptions) -> (RollingHashAlgorithm, StrongHashAlgorithm) {
+    let (weak_hash, strong_hash) = match options.format.unwrap_or_default() {
+        FileFormat::Native | FileFormat::Vcdiff => Default::default(),
+        FileFormat::Librsync => (RollingHashAlgorithm::RabinKarp, StrongHashAlgorithm::Blake2),
+    };
+
+    (
+        options.weak_hash.unwrap_or(weak_hash),
+        options.strong_hash.unwrap_or(strong_hash),
+    )
+}
+
+// Directories only have a native encoding
+fn check_native_format(options: &SignatureOptions) -> Result<(), AppError> {
+    match options.format.unwrap_or_default() {
+        FileFormat::Native => Ok(()),
+        format => Err(AppError::UnsupportedFile(format!(
+            "{} files describe a single file, not a directory",
+            format
+        ))),
+    }
+}
+
+// Builder of the signature of a basis file, `basis_len` is unknown for the standard input
+fn signature_builder(
+    options: &SignatureOptions,
+    basis_len: Option<usize>,
+) -> Result<SignatureBuilder, AppError> {
+    let chunk_size = options
+        .chunk_size
+        .or_else(|| basis_len.map(rdiff::auto_chunk_size))
+        .unwrap_or(DEFAULT_CHUNK_SIZE);
+
+    let (weak_hash, strong_hash) = default_hashes(options);
+
+    let builder = match options.cdc {
+        Some(cdc) => SignatureBuilder::new_cdc(cdc)?,
+        None => SignatureBuilder::new(chunk_size)?,
+    };
+
+    let mut builder = builder.weak_hash(weak_hash).strong_hash(strong_hash);
+
+    if let Some(hint_len) = options.hint_len {
+        builder = builder.hint_len(hint_len)?;
+    }
+
+    // The full strong hash is kept when the basis size is unknown
+    let strong_len = options.strong_len.or_else(|| {
+        let chunk_size = options.cdc.map_or(chunk_size, |cdc| cdc.avg_size);
+        basis_len.map(|basis_len| rdiff::auto_strong_len(basis_len, chunk_size, strong_hash))
+    });
+
+    match strong_len {
+        Some(strong_len) => builder.strong_len(strong_len),
+        None => Ok(builder),
+    }
+}
 
 fn produce_signature(
-    chunk_size: usize,
+    options: &SignatureOptions,
     old_file: &Path,
     signature_file: &Path,
 ) -> Result<(), AppError> {
-    let data = old_file.read_from_file()?;
+    if old_file.is_dir() {
+        check_native_format(options)?;
+
+        let signature = TreeSignature::from_dir(old_file, |basis_len| {
+            signature_builder(options, Some(basis_len))
+        })?;
+
+        return signature_file.write_to_file(signature.to_bytes()?);
+    }
+
+    let basis_len = match old_file == Path::new("-") {
+        true => None,
+        false => Some(fs::metadata(old_file)?.len() as usize),
+    };
+
+    let mut builder = signature_builder(options, basis_len)?;
+    builder.read_from(old_file.open_reader()?)?;
+    let signature = builder.finish()?;
+
+    let signature_data = match options.format.unwrap_or_default() {
+        FileFormat::Native | FileFormat::Vcdiff => signature.to_bytes()?,
+        FileFormat::Librsync => signature.to_librsync_bytes()?,
+    };
+
+    signature_file.write_to_file(signature_data)
+}
+
+// Refuses a signature built with other parameters than the ones given
+fn check_signature(options: &SignatureOptions, signature: &Signature) -> Result<(), AppError> {
+    if let Some(chunk_size) = options.chunk_size {
+        signature.check_chunk_size_equal(chunk_size)?;
+    }
+
+    if options.cdc.is_some() {
+        signature.check_cdc_equal(options.cdc)?;
+    }
+
+    if let Some(weak_hash) = options.weak_hash {
+        signature.check_weak_hash_equal(weak_hash)?;
+    }
 
-    let chunk_processor = ChunkProcessor::new(chunk_size);
+    if let Some(strong_hash) = options.strong_hash {
+        signature.check_strong_hash_equal(strong_hash)?;
+    }
+
+    if let Some(strong_len) = options.strong_len {
+        signature.check_strong_len_equal(strong_len)?;
+    }
 
-    let checksum_store = chunk_processor.produce_checksum(data)?;
+    if let Some(hint_len) = options.hint_len {
+        signature.check_hint_len_equal(hint_len)?;
+    }
 
-    signature_file.write_to_file(checksum_store.into_encoded()?)
+    Ok(())
 }
 
 pub fn produce_delta(
-    chunk_size: usize,
+    options: &SignatureOptions,
     signature_file: &Path,
     new_file: &Path,
     delta_file: &Path,
 ) -> Result<(), AppError> {
     let signature_data = signature_file.read_from_file()?;
 
-    let new_file_data = new_file.read_from_file()?;
+    if new_file.is_dir() {
+        check_native_format(options)?;
+
+        let signature = TreeSignature::from_bytes(&signature_data)?;
+
+        for entry in signature.entries() {
+            if let TreeEntryKind::File(signature) = &entry.kind {
+                check_signature(options, signature)?;
+            }
+        }
 
-    let checksum_store = signature_data.decode::<ChecksumStore>()?;
+        rdiff::tree_delta(&signature, new_file, delta_file.open_writer()?)?;
 
-    checksum_store.check_chunk_size_equal(chunk_size)?;
+        return Ok(());
+    }
+
+    let format = options.format.unwrap_or_default();
 
-    let checksum_indexed_store = checksum_store.produce_indexed_checksum();
+    let signature = match format {
+        FileFormat::Native | FileFormat::Vcdiff => Signature::from_bytes(&signature_data)?,
+        FileFormat::Librsync => Signature::from_librsync_bytes(&signature_data)?,
+    };
+    check_signature(options, &signature)?;
 
-    let delta = checksum_indexed_store.produce_delta(new_file_data)?;
+    let (reader, writer) = (new_file.open_reader()?, delta_file.open_writer()?);
 
-    let full_delta = delta.extend_missed_blocks(&checksum_store.data);
+    match format {
+        FileFormat::Native => rdiff::stream_delta(&signature, reader, writer)?,
+        FileFormat::Librsync => rdiff::stream_librsync_delta(&signature, reader, writer)?,
+        FileFormat::Vcdiff => rdiff::stream_vcdiff_delta(&signature, reader, writer)?,
+    };
 
-    delta_file.write_to_file(full_delta.into_encoded()?)
+    Ok(())
+}
+
+fn produce_patch(
+    options: &SignatureOptions,
+    basis_file: &Path,
+    delta_file: &Path,
+    output_file: &Path,
+) -> Result<(), AppError> {
+    let delta_data = delta_file.read_from_file()?;
+
+    if basis_file.is_dir() {
+        check_native_format(options)?;
+
+        return rdiff::patch_tree(basis_file, &delta_data, output_file);
+    }
+
+    let basis_data = basis_file.read_from_file()?;
+
+    let patched = match options.format.unwrap_or_default() {
+        FileFormat::Native => rdiff::patch(&basis_data, &Delta::from_bytes(&delta_data)?)?,
+        FileFormat::Librsync => rdiff::patch_librsync(&basis_data, &delta_data)?,
+        FileFormat::Vcdiff => rdiff::patch_vcdiff(&basis_data, &delta_data)?,
+    };
+
+    output_file.write_to_file(patched)
+}
+
+fn produce_compose(
+    options: &SignatureOptions,
+    delta_files: &[PathBuf],
+    output_file: &Path,
+) -> Result<(), AppError> {
+    if options.format.unwrap_or_default() != FileFormat::Native {
+        return Err(AppError::UnsupportedFile(String::from(
+            "Only native deltas can be composed",
+        )));
+    }
+
+    let deltas = delta_files
+        .iter()
+        .map(|delta_file| Delta::from_bytes(&delta_file.read_from_file()?))
+        .collect::<Result<Vec<Delta>, AppError>>()?;
+
+    output_file.write_to_file(rdiff::compose(&deltas)?.to_bytes()?)
+}
+
+fn produce_diff(
+    options: &SignatureOptions,
+    old_file: &Path,
+    new_file: &Path,
+    delta_file: &Path,
+) -> Result<(), AppError> {
+    let basis_data = old_file.read_from_file()?;
+
+    let block_size = options
+        .chunk_size
+        .unwrap_or_else(|| rdiff::auto_diff_block_size(basis_data.len()));
+
+    let (weak_hash, _) = default_hashes(options);
+    let (reader, writer) = (new_file.open_reader()?, delta_file.open_writer()?);
+
+    match options.format.unwrap_or_default() {
+        FileFormat::Native => {
+            rdiff::diff(&basis_data, reader, writer, block_size, weak_hash)?;
+        }
+        FileFormat::Librsync => {
+            let index =
+                BasisIndex::new(&basis_data, block_size, weak_hash)?.with_target_window(None);
+            let mut writer = LibrsyncDeltaWriter::new(writer)?;
+
+            index.stream_delta(reader, |op| writer.write_op(&op))?;
+            writer.finish()?;
+        }
+        FileFormat::Vcdiff => {
+            let index = BasisIndex::new(&basis_data, block_size, weak_hash)?
+                .with_target_window(Some(rdiff::VCDIFF_WINDOW_LEN));
+            let mut writer = VcdiffWriter::new(writer, basis_data.len())?;
+
+            index.stream_delta(reader, |op| writer.write_op(&op))?;
+            writer.finish()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn produce_store(
+    options: &SignatureOptions,
+    store_dir: &Path,
+    cmd: StoreCommand,
+) -> Result<(), AppError> {
+    if let StoreCommand::Init = cmd {
+        let cdc = match options.cdc {
+            Some(cdc) => cdc,
+            None => CdcParams::from_avg(DEFAULT_STORE_CHUNK_SIZE)?,
+        };
+
+        ChunkStore::create(store_dir, cdc, options.strong_hash.unwrap_or_default())?;
+        return Ok(());
+    }
+
+    let mut store = ChunkStore::open(store_dir)?;
+
+    match cmd {
+        StoreCommand::Init => {}
+        StoreCommand::Put { name, file } => {
+            let stats = store.put(&name, file.open_reader()?)?;
+            println!(
+                "{} chunks, {} bytes: {} new chunks, {} new bytes",
+                stats.chunks, stats.bytes, stats.new_chunks, stats.new_bytes
+            );
+        }
+        StoreCommand::Get { name, output_file } => {
+            store.get(&name, output_file.open_writer()?)?;
+        }
+        StoreCommand::Delete { name } => store.delete(&name)?,
+        StoreCommand::Gc => {
+            let stats = store.gc()?;
+            println!(
+                "Removed {} chunks, {} bytes",
+                stats.removed_chunks, stats.removed_bytes
+            );
+        }
+        StoreCommand::Stats => {
+            let stats = store.stats()?;
+            println!(
+                "{} files, {} bytes\n{} chunks, {} bytes\nDeduplication ratio {:.2}",
+                stats.files,
+                stats.logical_bytes,
+                stats.chunks,
+                stats.stored_bytes,
+                stats.dedup_ratio()
+            );
+        }
+    }
+
+    Ok(())
 }
 
 fn main() -> Result<(), AppError> {
@@ -62,20 +324,43 @@ fn main() -> Result<(), AppError> {
         SubCommand::Signature {
             old_file,
             signature_file,
-        } => produce_signature(
-            args.chunk_size,
-            old_file.as_path(),
-            signature_file.as_path(),
-        ),
+        } => produce_signature(&args.options, old_file.as_path(), signature_file.as_path()),
         SubCommand::Delta {
             signature_file,
             new_file,
             delta_file,
         } => produce_delta(
-            args.chunk_size,
+            &args.options,
             signature_file.as_path(),
             new_file.as_path(),
             delta_file.as_path(),
         ),
+        SubCommand::Patch {
+            basis_file,
+            delta_file,
+            output_file,
+        } => produce_patch(
+            &args.options,
+            basis_file.as_path(),
+            delta_file.as_path(),
+            output_file.as_path(),
+        ),
+        SubCommand::Diff {
+            old_file,
+            new_file,
+            delta_file,
+        } => produce_diff(
+            &args.options,
+            old_file.as_path(),
+            new_file.as_path(),
+            delta_file.as_path(),
+        ),
+        SubCommand::Compose {
+            delta_files,
+            output_file,
+        } => produce_compose(&args.options, &delta_files, output_file.as_path()),
+        SubCommand::Store { store_dir, cmd } => {
+            produce_store(&args.options, store_dir.as_path(), cmd)
+        }
     }
 }
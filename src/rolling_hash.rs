@@ -0,0 +1,318 @@
+use std::{fmt, str::FromStr};
+
+use adler32::RollingAdler32;
+use serde::{Deserialize, Serialize};
+
+#[cfg(test)]
+use crate::test_support::test_data;
+
+/// Weak checksum of a fixed size window, cheap to slide over the data one byte at a time
+pub trait RollingHash {
+    /// Restarts the hash on `window`, which sets the size of the following rolls
+    fn init(&mut self, window: &[u8]);
+    /// Drops `outgoing`, the first byte of the window, and appends `incoming`
+    fn roll(&mut self, outgoing: u8, incoming: u8);
+    fn digest(&self) -> u32;
+}
+
+// Modulus of the Adler-32 sums
+const ADLER32_BASE: usize = 65521;
+
+pub struct Adler32 {
+    rolling: RollingAdler32,
+    size: usize,
+}
+
+impl Default for Adler32 {
+    fn default() -> Self {
+        Adler32 {
+            rolling: RollingAdler32::new(),
+            size: 0,
+        }
+    }
+}
+
+impl RollingHash for Adler32 {
+    fn init(&mut self, window: &[u8]) {
+        self.rolling = RollingAdler32::from_buffer(window);
+        self.size = window.len();
+    }
+
+    fn roll(&mut self, outgoing: u8, incoming: u8) {
+        // RollingAdler32::remove overflows for windows longer than the modulus, the sums only need it modulo the base
+        self.rolling.remove(self.size % ADLER32_BASE, outgoing);
+        self.rolling.update(incoming);
+    }
+
+    fn digest(&self) -> u32 {
+        self.rolling.hash()
+    }
+}
+
+// Offset added to each byte by the rsync checksum, so runs of zeros still move the sums
+const ROLLSUM_CHAR_OFFSET: u32 = 31;
+
+/// Checksum of rsync and librsync: Adler-32 like sums kept modulo 2^16 instead of a prime
+#[derive(Default)]
+pub struct Rollsum {
+    s1: u32,
+    s2: u32,
+    count: u32,
+}
+
+impl RollingHash for Rollsum {
+    fn init(&mut self, window: &[u8]) {
+        *self = Rollsum::default();
+
+        for byte in window {
+            self.s1 = self.s1.wrapping_add(*byte as u32 + ROLLSUM_CHAR_OFFSET);
+            self.s2 = self.s2.wrapping_add(self.s1);
+        }
+        self.count = window.len() as u32;
+    }
+
+    fn roll(&mut self, outgoing: u8, incoming: u8) {
+        self.s1 = self
+            .s1
+            .wrapping_add(incoming as u32)
+            .wrapping_sub(outgoing as u32);
+        self.s2 = self.s2.wrapping_add(self.s1).wrapping_sub(
+            self.count
+                .wrapping_mul(outgoing as u32 + ROLLSUM_CHAR_OFFSET),
+        );
+    }
+
+    fn digest(&self) -> u32 {
+        (self.s2 << 16) | (self.s1 & 0xffff)
+    }
+}
+
+// Constants of the librsync polynomial hash, the adjustment removes the seed power gained on each roll
+const RABINKARP_SEED: u32 = 1;
+const RABINKARP_MULT: u32 = 0x0810_4225;
+const RABINKARP_ADJ: u32 = RABINKARP_MULT - RABINKARP_SEED;
+
+/// Polynomial hash modulo 2^32, as used by librsync for its default signatures
+pub struct RabinKarp {
+    hash: u32,
+    mult: u32, // RABINKARP_MULT raised to the window size
+}
+
+impl Default for RabinKarp {
+    fn default() -> Self {
+        RabinKarp {
+            hash: RABINKARP_SEED,
+            mult: 1,
+        }
+    }
+}
+
+impl RollingHash for RabinKarp {
+    fn init(&mut self, window: &[u8]) {
+        *self = RabinKarp::default();
+
+        for byte in window {
+            self.hash = self
+                .hash
+                .wrapping_mul(RABINKARP_MULT)
+                .wrapping_add(*byte as u32);
+            self.mult = self.mult.wrapping_mul(RABINKARP_MULT);
+        }
+    }
+
+    fn roll(&mut self, outgoing: u8, incoming: u8) {
+        self.hash = self
+            .hash
+            .wrapping_mul(RABINKARP_MULT)
+            .wrapping_add(incoming as u32)
+            .wrapping_sub(
+                self.mult
+                    .wrapping_mul((outgoing as u32).wrapping_add(RABINKARP_ADJ)),
+            );
+    }
+
+    fn digest(&self) -> u32 {
+        self.hash
+    }
+}
+
+// Pseudo-random value of each byte, from a splitmix64 sequence so that tables built from it stay portable
+pub(crate) const fn random_table(seed: u64) -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state = seed;
+    let mut i = 0;
+
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+
+    table
+}
+
+const BUZHASH_TABLE: [u32; 256] = buzhash_table();
+
+const fn buzhash_table() -> [u32; 256] {
+    let random = random_table(0);
+    let mut table = [0; 256];
+    let mut i = 0;
+
+    while i < table.len() {
+        table[i] = (random[i] >> 32) as u32;
+        i += 1;
+    }
+
+    table
+}
+
+/// Cyclic polynomial hash: XOR of the rotated table values of the bytes
+#[derive(Default)]
+pub struct Buzhash {
+    hash: u32,
+    size: u32,
+}
+
+impl RollingHash for Buzhash {
+    fn init(&mut self, window: &[u8]) {
+        self.hash = window.iter().fold(0, |hash, byte| {
+            hash.rotate_left(1) ^ BUZHASH_TABLE[*byte as usize]
+        });
+        self.size = window.len() as u32;
+    }
+
+    fn roll(&mut self, outgoing: u8, incoming: u8) {
+        self.hash = self.hash.rotate_left(1)
+            ^ BUZHASH_TABLE[outgoing as usize].rotate_left(self.size % 32)
+            ^ BUZHASH_TABLE[incoming as usize];
+    }
+
+    fn digest(&self) -> u32 {
+        self.hash
+    }
+}
+
+/// Weak hash a signature is built with, stored in signature and delta files by its id
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RollingHashAlgorithm {
+    #[default]
+    Adler32,
+    Rollsum,
+    RabinKarp,
+    Buzhash,
+}
+
+impl RollingHashAlgorithm {
+    pub const ALL: [RollingHashAlgorithm; 4] = [
+        RollingHashAlgorithm::Adler32,
+        RollingHashAlgorithm::Rollsum,
+        RollingHashAlgorithm::RabinKarp,
+        RollingHashAlgorithm::Buzhash,
+    ];
+
+    pub fn id(self) -> u8 {
+        match self {
+            RollingHashAlgorithm::Adler32 => 1,
+            RollingHashAlgorithm::Rollsum => 2,
+            RollingHashAlgorithm::RabinKarp => 3,
+            RollingHashAlgorithm::Buzhash => 4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        RollingHashAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.id() == id)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RollingHashAlgorithm::Adler32 => "adler32",
+            RollingHashAlgorithm::Rollsum => "rollsum",
+            RollingHashAlgorithm::RabinKarp => "rabinkarp",
+            RollingHashAlgorithm::Buzhash => "buzhash",
+        }
+    }
+
+    /// Fresh roller, to be started with [`RollingHash::init`]
+    pub fn roller(self) -> Box<dyn RollingHash> {
+        match self {
+            RollingHashAlgorithm::Adler32 => Box::<Adler32>::default(),
+            RollingHashAlgorithm::Rollsum => Box::<Rollsum>::default(),
+            RollingHashAlgorithm::RabinKarp => Box::<RabinKarp>::default(),
+            RollingHashAlgorithm::Buzhash => Box::<Buzhash>::default(),
+        }
+    }
+
+    pub fn hash(self, data: &[u8]) -> u32 {
+        let mut roller = self.roller();
+        roller.init(data);
+        roller.digest()
+    }
+}
+
+impl fmt::Display for RollingHashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for RollingHashAlgorithm {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        RollingHashAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name() == value)
+            .ok_or_else(|| {
+                format!(
+                    "Unknown weak hash {}, expected one of: {}",
+                    value,
+                    RollingHashAlgorithm::ALL
+                        .map(|algorithm| algorithm.name())
+                        .join(", ")
+                )
+            })
+    }
+}
+
+#[test]
+fn check_rolling_matches_full_hash() {
+    let data = test_data(200_000, 24);
+
+    for algorithm in RollingHashAlgorithm::ALL {
+        for size in [1, 16, 512, ADLER32_BASE + 7] {
+            let mut roller = algorithm.roller();
+            roller.init(&data[..size]);
+
+            for start in 1..200 {
+                roller.roll(data[start - 1], data[start + size - 1]);
+                assert_eq!(
+                    roller.digest(),
+                    algorithm.hash(&data[start..start + size]),
+                    "{} with a {} bytes window",
+                    algorithm,
+                    size
+                );
+            }
+        }
+
+        assert_eq!(
+            RollingHashAlgorithm::from_id(algorithm.id()),
+            Some(algorithm)
+        );
+        assert_eq!(algorithm.name().parse(), Ok(algorithm));
+    }
+}
+
+#[test]
+fn check_known_digests() {
+    let data = "abcdefghijklmnopqrstuvwxyz".as_bytes();
+
+    assert_eq!(RollingHashAlgorithm::Adler32.hash(data), 0x9086_0b20);
+    assert_eq!(RollingHashAlgorithm::Rollsum.hash(data), 0xbaed_0e45);
+    assert_eq!(RollingHashAlgorithm::RabinKarp.hash(data), 0x18be_1c4c);
+}
@@ -0,0 +1,397 @@
+use std::io::{ErrorKind, Read};
+
+use crate::{
+    app_error::AppError,
+    cdc::CdcParams,
+    chunk_processor::{ChecksumProducer, ChunkProcessor, InitialEmptyData},
+    decode::Decoded,
+    encode::Encoded,
+    file_format::FileHeader,
+    rolling_hash::RollingHashAlgorithm,
+    strong_hash::StrongHashAlgorithm,
+    types::ChecksumStore,
+};
+
+#[cfg(test)]
+use crate::test_support::{literal_len, test_data};
+
+// Bounds of the automatically selected chunk size
+const MIN_AUTO_CHUNK_SIZE: usize = 256;
+const MAX_AUTO_CHUNK_SIZE: usize = 128 * 1024;
+
+/// Chunk size suited to a basis file of `basis_len` bytes: its square root rounded up to a
+/// multiple of 8, so the signature grows with the square root of the file size as well
+pub fn auto_chunk_size(basis_len: usize) -> usize {
+    let root = (basis_len as f64).sqrt().ceil() as usize;
+
+    (root.div_ceil(8) * 8).clamp(MIN_AUTO_CHUNK_SIZE, MAX_AUTO_CHUNK_SIZE)
+}
+
+// Floor of the base 2 logarithm, 0 for 0
+fn log2(value: usize) -> usize {
+    value.checked_ilog2().unwrap_or(0) as usize
+}
+
+/// Strong hash bytes to keep for a basis file of `basis_len` bytes split into `chunk_size` bytes
+/// chunks, the minimum librsync deems safe: enough bits for both the file size and the chunk
+/// count, 16 bits of margin, capped by the digest length
+pub fn auto_strong_len(
+    basis_len: usize,
+    chunk_size: usize,
+    strong_hash: StrongHashAlgorithm,
+) -> usize {
+    let bits = log2(basis_len + (1 << 24)) + log2(basis_len / chunk_size.max(1) + 1);
+
+    (2 + bits.div_ceil(8)).min(strong_hash.hasher().digest_len())
+}
+
+/// Weak and strong checksums of every chunk of a basis file
+#[derive(Debug)]
+pub struct Signature {
+    pub(crate) checksums: ChunkProcessor<ChecksumStore>,
+}
+
+impl Signature {
+    /// Splits `data` into `chunk_size` bytes chunks and checksums each of them
+    pub fn new(data: &[u8], chunk_size: usize) -> Result<Self, AppError> {
+        let checksums = ChunkProcessor::new(chunk_size).produce_checksum(data)?;
+
+        Ok(Signature { checksums })
+    }
+
+    /// Same as [`Signature::new`] for data read from `reader`, holding a single chunk in memory at a time
+    pub fn from_reader<R: Read>(reader: R, chunk_size: usize) -> Result<Self, AppError> {
+        let mut builder = SignatureBuilder::new(chunk_size)?;
+        builder.read_from(reader)?;
+
+        builder.finish()
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.checksums.chunk_size
+    }
+
+    /// Length of the basis file the signature was built from, `None` for librsync signatures
+    /// which don't record it
+    pub fn basis_len(&self) -> Option<usize> {
+        match self.checksums.unknown_tail {
+            true => None,
+            false => Some(self.chunks_len()),
+        }
+    }
+
+    // Bytes the chunks cover, the basis length of the native signatures
+    pub(crate) fn chunks_len(&self) -> usize {
+        self.checksums
+            .data
+            .iter()
+            .map(|checksum| checksum.len)
+            .sum()
+    }
+
+    pub fn check_chunk_size_equal(&self, chunk_size: usize) -> Result<(), AppError> {
+        self.checksums.check_chunk_size_equal(chunk_size)
+    }
+
+    /// Sizes of the content-defined chunks, `None` for fixed size ones
+    pub fn cdc(&self) -> Option<CdcParams> {
+        self.checksums.cdc
+    }
+
+    pub fn check_cdc_equal(&self, cdc: Option<CdcParams>) -> Result<(), AppError> {
+        self.checksums.check_cdc_equal(cdc)
+    }
+
+    pub fn weak_hash(&self) -> RollingHashAlgorithm {
+        self.checksums.weak_hash
+    }
+
+    pub fn check_weak_hash_equal(&self, weak_hash: RollingHashAlgorithm) -> Result<(), AppError> {
+        self.checksums.check_weak_hash_equal(weak_hash)
+    }
+
+    pub fn strong_hash(&self) -> StrongHashAlgorithm {
+        self.checksums.strong_hash
+    }
+
+    pub fn check_strong_hash_equal(
+        &self,
+        strong_hash: StrongHashAlgorithm,
+    ) -> Result<(), AppError> {
+        self.checksums.check_strong_hash_equal(strong_hash)
+    }
+
+    /// Bytes of each strong hash stored in the signature
+    pub fn strong_len(&self) -> usize {
+        self.checksums.strong_len
+    }
+
+    pub fn check_strong_len_equal(&self, strong_len: usize) -> Result<(), AppError> {
+        self.checksums.check_strong_len_equal(strong_len)
+    }
+
+    /// Length of the sub-blocks whose hashes the signature keeps, 0 without hints
+    pub fn hint_len(&self) -> usize {
+        self.checksums.hint_len
+    }
+
+    pub fn check_hint_len_equal(&self, hint_len: usize) -> Result<(), AppError> {
+        self.checksums.check_hint_len_equal(hint_len)
+    }
+
+    // Fails for librsync signatures, native files record the basis length
+    pub(crate) fn header(&self) -> Result<FileHeader, AppError> {
+        let basis_len = self.basis_len().ok_or_else(|| {
+            AppError::UnsupportedFile(String::from(
+                "librsync signatures don't record the basis length: Please write their deltas in the librsync format",
+            ))
+        })?;
+
+        Ok(FileHeader::new(&self.checksums, basis_len))
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, AppError> {
+        self.checksums.to_encoded(&self.header()?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, AppError> {
+        let (_, checksums) = bytes.decode::<ChecksumStore>()?;
+
+        Ok(Signature { checksums })
+    }
+}
+
+/// Incremental signature of a basis file fed in pieces of any size
+pub struct SignatureBuilder {
+    checksums: ChunkProcessor<ChecksumStore>,
+    pending: Vec<u8>, // Start of the next chunks, never longer than chunk_size
+}
+
+impl SignatureBuilder {
+    pub fn new(chunk_size: usize) -> Result<Self, AppError> {
+        SignatureBuilder::from_processor(ChunkProcessor::new(chunk_size))
+    }
+
+    /// Builder cutting content-defined chunks instead of fixed size ones
+    pub fn new_cdc(cdc: CdcParams) -> Result<Self, AppError> {
+        SignatureBuilder::from_processor(ChunkProcessor::new(cdc.max_size).with_cdc(cdc))
+    }
+
+    pub(crate) fn from_processor(
+        processor: ChunkProcessor<InitialEmptyData>,
+    ) -> Result<Self, AppError> {
+        processor.check_chunk_size_valid()?;
+
+        Ok(SignatureBuilder {
+            pending: Vec::with_capacity(processor.chunk_size),
+            checksums: processor.produce_checksum(&[])?,
+        })
+    }
+
+    /// Selects the rolling weak hash of the chunks, before any data is fed
+    pub fn weak_hash(mut self, weak_hash: RollingHashAlgorithm) -> Self {
+        self.checksums.weak_hash = weak_hash;
+        self
+    }
+
+    /// Selects the strong hash of the chunks, before any data is fed, and keeps its full digest
+    pub fn strong_hash(mut self, strong_hash: StrongHashAlgorithm) -> Self {
+        self.checksums.strong_hash = strong_hash;
+        self.checksums.strong_len = strong_hash.hasher().digest_len();
+        self
+    }
+
+    /// Keeps only the first `strong_len` bytes of each strong hash, to be set after [`SignatureBuilder::strong_hash`]
+    pub fn strong_len(mut self, strong_len: usize) -> Result<Self, AppError> {
+        self.checksums.strong_len = strong_len;
+        self.checksums.check_strong_len_valid()?;
+
+        Ok(self)
+    }
+
+    /// Also keeps a short hash of every `hint_len` bytes sub-block of the chunks, so deltas only send
+    /// the sub-blocks that differ next to a matching chunk, before any data is fed
+    pub fn hint_len(mut self, hint_len: usize) -> Result<Self, AppError> {
+        self.checksums.hint_len = hint_len;
+        self.checksums.check_hint_len_valid()?;
+
+        Ok(self)
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) -> Result<(), AppError> {
+        let chunk_size = self.checksums.chunk_size;
+
+        while !data.is_empty() {
+            let taken = data.len().min(chunk_size - self.pending.len());
+            self.pending.extend_from_slice(&data[..taken]);
+            data = &data[taken..];
+
+            // A full buffer is enough to find where the next chunk ends
+            if self.pending.len() == chunk_size {
+                self.push_pending_chunk()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn push_pending_chunk(&mut self) -> Result<(), AppError> {
+        let len = self.checksums.cut_len(&self.pending);
+        self.checksums.push_chunk(&self.pending[..len])?;
+        self.pending.drain(..len);
+
+        Ok(())
+    }
+
+    /// Feeds everything `reader` yields until the end of its data
+    pub fn read_from<R: Read>(&mut self, mut reader: R) -> Result<(), AppError> {
+        let mut buf = vec![0; self.checksums.chunk_size];
+
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => self.update(&buf[..n])?,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(AppError::from(err)),
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> Result<Signature, AppError> {
+        while !self.pending.is_empty() {
+            self.push_pending_chunk()?;
+        }
+
+        Ok(Signature {
+            checksums: self.checksums,
+        })
+    }
+}
+
+#[test]
+fn test_builder_matches_in_memory_signature() {
+    let data = test_data(10_000, 25);
+
+    let expected = Signature::new(&data, 512).unwrap().to_bytes().unwrap();
+
+    let mut builder = SignatureBuilder::new(512).unwrap();
+    for piece in data.chunks(77) {
+        builder.update(piece).unwrap();
+    }
+    assert_eq!(builder.finish().unwrap().to_bytes().unwrap(), expected);
+
+    let from_reader = Signature::from_reader(&data[..], 512).unwrap();
+    assert_eq!(from_reader.to_bytes().unwrap(), expected);
+}
+
+#[test]
+fn test_auto_chunk_size() {
+    assert_eq!(auto_chunk_size(0), MIN_AUTO_CHUNK_SIZE);
+    assert_eq!(auto_chunk_size(2 * 1024), MIN_AUTO_CHUNK_SIZE);
+    assert_eq!(auto_chunk_size(1024 * 1024), 1024);
+    assert_eq!(auto_chunk_size(1_000_000), 1000);
+    assert_eq!(auto_chunk_size(1_000_001), 1008);
+    assert_eq!(
+        auto_chunk_size(100 * 1024 * 1024 * 1024),
+        MAX_AUTO_CHUNK_SIZE
+    );
+}
+
+#[test]
+fn test_auto_strong_len() {
+    let keccak = StrongHashAlgorithm::Keccak256;
+
+    assert_eq!(auto_strong_len(0, 512, keccak), 5);
+    assert_eq!(auto_strong_len(1024 * 1024, 1024, keccak), 7);
+    assert_eq!(auto_strong_len(1 << 40, 1 << 20, keccak), 10);
+    assert_eq!(
+        auto_strong_len(usize::MAX - (1 << 24), 1, StrongHashAlgorithm::Xxh3),
+        16
+    );
+}
+
+#[test]
+fn test_truncated_strong_hash_shrinks_signature() {
+    let data = test_data(100_000, 26);
+    let new_data = [&data[300..60_000], "changed".as_bytes(), &data[60_000..]].concat();
+
+    let full = Signature::new(&data, 512).unwrap();
+
+    let mut builder = SignatureBuilder::new(512).unwrap().strong_len(6).unwrap();
+    builder.update(&data).unwrap();
+    let truncated = Signature::from_bytes(&builder.finish().unwrap().to_bytes().unwrap()).unwrap();
+    assert_eq!(truncated.strong_len(), 6);
+
+    // 4 bytes of weak hash and the kept strong hash bytes for each of the 196 chunks
+    let full_len = full.to_bytes().unwrap().len();
+    assert_eq!(
+        full_len - truncated.to_bytes().unwrap().len(),
+        196 * (32 - 6)
+    );
+    assert!(full_len < 196 * 37);
+
+    let delta = crate::Delta::new(&truncated, &new_data).unwrap();
+    assert_eq!(
+        delta.ops(),
+        crate::Delta::new(&full, &new_data).unwrap().ops()
+    );
+    assert_eq!(delta.apply(&data).unwrap(), new_data);
+
+    assert!(matches!(
+        SignatureBuilder::new(512).unwrap().strong_len(33),
+        Err(AppError::IncompatibleHash(_))
+    ));
+    assert!(matches!(
+        SignatureBuilder::new(512)
+            .unwrap()
+            .strong_hash(StrongHashAlgorithm::Xxh3)
+            .strong_len(17),
+        Err(AppError::IncompatibleHash(_))
+    ));
+}
+
+#[test]
+fn test_hints_narrow_literals_to_sub_blocks() {
+    let data = test_data(100_000, 9);
+    let mut new_data = [&data[..60_000], "inserted".as_bytes(), &data[60_000..]].concat();
+    for offset in [10_100, 30_700, 81_000] {
+        new_data[offset] ^= 0xff;
+    }
+
+    let plain = crate::Delta::new(&Signature::new(&data, 1024).unwrap(), &new_data).unwrap();
+
+    let mut fixed = SignatureBuilder::new(1024).unwrap().hint_len(64).unwrap();
+    let mut content_defined = SignatureBuilder::new_cdc(CdcParams::from_avg(1024).unwrap())
+        .unwrap()
+        .hint_len(64)
+        .unwrap();
+    fixed.update(&data).unwrap();
+    content_defined.update(&data).unwrap();
+
+    for builder in [fixed, content_defined] {
+        let signature =
+            Signature::from_bytes(&builder.finish().unwrap().to_bytes().unwrap()).unwrap();
+        assert_eq!(signature.hint_len(), 64);
+
+        let delta = crate::Delta::new(&signature, &new_data).unwrap();
+        assert_eq!(delta.apply(&data).unwrap(), new_data);
+
+        // A sub-block for each changed byte and at most two around the insertion instead of whole chunks
+        assert!(
+            literal_len(&delta) <= 5 * 64 + 8,
+            "{} literal bytes",
+            literal_len(&delta)
+        );
+    }
+
+    assert!(literal_len(&plain) >= 3 * 1024);
+
+    assert!(matches!(
+        SignatureBuilder::new(512).unwrap().hint_len(512),
+        Err(AppError::IncompatibleChunkSize(_))
+    ));
+
+    let mut builder = SignatureBuilder::new(512).unwrap().hint_len(64).unwrap();
+    builder.update(&data).unwrap();
+    assert!(builder.finish().unwrap().to_librsync_bytes().is_err());
+}
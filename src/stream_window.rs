@@ -0,0 +1,99 @@
+use std::io::{ErrorKind, Read};
+
+use crate::app_error::AppError;
+
+// Room read into at first, the buffer then at most doubles with each read so that it follows the data actually received
+const MIN_READ_LEN: usize = 64 * 1024;
+
+// Sliding view over data read from a stream: the bytes from the current position up to a few windows ahead
+pub struct StreamWindow<R> {
+    reader: R,
+    buf: Vec<u8>,
+    start: usize, // Current position inside buf
+    eof: bool,
+}
+
+impl<R: Read> StreamWindow<R> {
+    pub fn new(reader: R) -> Self {
+        StreamWindow {
+            reader,
+            buf: vec![],
+            start: 0,
+            eof: false,
+        }
+    }
+
+    // Buffers `len` bytes past the current position, or everything left when the stream ends earlier
+    pub fn fill(&mut self, len: usize) -> Result<(), AppError> {
+        if self.eof || self.buf.len() - self.start >= len {
+            return Ok(());
+        }
+
+        self.buf.drain(..self.start);
+        self.start = 0;
+
+        // Reading twice the window keeps the memory bounded while the drain above stays amortized
+        let target = len.saturating_mul(2);
+
+        while self.buf.len() < len && !self.eof {
+            let filled = self.buf.len();
+            self.buf
+                .resize(target.min(filled.max(MIN_READ_LEN).saturating_mul(2)), 0);
+
+            match self.reader.read(&mut self.buf[filled..]) {
+                Ok(0) => {
+                    self.buf.truncate(filled);
+                    self.eof = true;
+                }
+                Ok(n) => self.buf.truncate(filled + n),
+                Err(err) if err.kind() == ErrorKind::Interrupted => self.buf.truncate(filled),
+                Err(err) => {
+                    self.buf.truncate(filled);
+                    return Err(AppError::from(err));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.buf[self.start..]
+    }
+
+    pub fn advance(&mut self, n: usize) {
+        self.start += n;
+    }
+}
+
+#[test]
+fn check_window_over_short_reads() {
+    // Reader handing out at most 3 bytes per call
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(3).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    let value = (0..100).collect::<Vec<u8>>();
+    let mut window = StreamWindow::new(Trickle(&value));
+
+    for pos in 0..100 {
+        window.fill(10).unwrap();
+        let end = (pos + 10).min(100);
+
+        assert!(window.data().len() >= end - pos);
+        assert_eq!(&window.data()[..end - pos], &value[pos..end]);
+        assert!(window.buf.len() <= 20);
+
+        window.advance(1);
+    }
+
+    window.fill(10).unwrap();
+    assert!(window.data().is_empty());
+}
@@ -0,0 +1,223 @@
+use std::{fmt, str::FromStr};
+
+use blake2::{digest::consts::U32, Blake2b};
+use md4::Md4 as Md4Digest;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256 as Sha256Digest;
+use sha3::{Digest, Keccak256 as Keccak256Digest};
+
+/// Collision resistant checksum confirming the chunks located by the weak checksum
+pub trait StrongHash {
+    /// Length of the digest in bytes
+    fn digest_len(&self) -> usize;
+    fn digest(&self, data: &[u8]) -> Vec<u8>;
+}
+
+pub struct Keccak256;
+
+impl StrongHash for Keccak256 {
+    fn digest_len(&self) -> usize {
+        32
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        Keccak256Digest::digest(data).to_vec()
+    }
+}
+
+pub struct Blake3;
+
+impl StrongHash for Blake3 {
+    fn digest_len(&self) -> usize {
+        32
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        blake3::hash(data).as_bytes().to_vec()
+    }
+}
+
+// 128 bits variant, not cryptographic but several times faster than the others
+pub struct Xxh3;
+
+impl StrongHash for Xxh3 {
+    fn digest_len(&self) -> usize {
+        16
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        xxhash_rust::xxh3::xxh3_128(data).to_be_bytes().to_vec()
+    }
+}
+
+pub struct Sha256;
+
+impl StrongHash for Sha256 {
+    fn digest_len(&self) -> usize {
+        32
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        Sha256Digest::digest(data).to_vec()
+    }
+}
+
+// Broken as a cryptographic hash, only kept to exchange signatures with librsync
+pub struct Md4;
+
+impl StrongHash for Md4 {
+    fn digest_len(&self) -> usize {
+        16
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        Md4Digest::digest(data).to_vec()
+    }
+}
+
+// BLAKE2b with a 256 bits output, the strong sum of librsync signatures
+pub struct Blake2;
+
+impl StrongHash for Blake2 {
+    fn digest_len(&self) -> usize {
+        32
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        Blake2b::<U32>::digest(data).to_vec()
+    }
+}
+
+/// Strong hash a signature is built with, stored in signature and delta files by its id
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StrongHashAlgorithm {
+    #[default]
+    Keccak256,
+    Blake3,
+    Xxh3,
+    Sha256,
+    Md4,
+    Blake2,
+}
+
+impl StrongHashAlgorithm {
+    pub const ALL: [StrongHashAlgorithm; 6] = [
+        StrongHashAlgorithm::Keccak256,
+        StrongHashAlgorithm::Blake3,
+        StrongHashAlgorithm::Xxh3,
+        StrongHashAlgorithm::Sha256,
+        StrongHashAlgorithm::Md4,
+        StrongHashAlgorithm::Blake2,
+    ];
+
+    pub fn id(self) -> u8 {
+        match self {
+            StrongHashAlgorithm::Keccak256 => 1,
+            StrongHashAlgorithm::Blake3 => 2,
+            StrongHashAlgorithm::Xxh3 => 3,
+            StrongHashAlgorithm::Sha256 => 4,
+            StrongHashAlgorithm::Md4 => 5,
+            StrongHashAlgorithm::Blake2 => 6,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        StrongHashAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.id() == id)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StrongHashAlgorithm::Keccak256 => "keccak256",
+            StrongHashAlgorithm::Blake3 => "blake3",
+            StrongHashAlgorithm::Xxh3 => "xxh3",
+            StrongHashAlgorithm::Sha256 => "sha256",
+            StrongHashAlgorithm::Md4 => "md4",
+            StrongHashAlgorithm::Blake2 => "blake2",
+        }
+    }
+
+    pub fn hasher(self) -> &'static dyn StrongHash {
+        match self {
+            StrongHashAlgorithm::Keccak256 => &Keccak256,
+            StrongHashAlgorithm::Blake3 => &Blake3,
+            StrongHashAlgorithm::Xxh3 => &Xxh3,
+            StrongHashAlgorithm::Sha256 => &Sha256,
+            StrongHashAlgorithm::Md4 => &Md4,
+            StrongHashAlgorithm::Blake2 => &Blake2,
+        }
+    }
+
+    pub fn hash(self, data: &[u8]) -> Vec<u8> {
+        self.hasher().digest(data)
+    }
+}
+
+impl fmt::Display for StrongHashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for StrongHashAlgorithm {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        StrongHashAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name() == value)
+            .ok_or_else(|| {
+                format!(
+                    "Unknown strong hash {}, expected one of: {}",
+                    value,
+                    StrongHashAlgorithm::ALL
+                        .map(|algorithm| algorithm.name())
+                        .join(", ")
+                )
+            })
+    }
+}
+
+#[test]
+fn check_known_digests() {
+    let expected = [
+        (
+            StrongHashAlgorithm::Keccak256,
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470",
+        ),
+        (
+            StrongHashAlgorithm::Blake3,
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+        ),
+        (
+            StrongHashAlgorithm::Xxh3,
+            "99aa06d3014798d86001c324468d497f",
+        ),
+        (
+            StrongHashAlgorithm::Sha256,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        ),
+        (StrongHashAlgorithm::Md4, "31d6cfe0d16ae931b73c59d7e0c089c0"),
+        (
+            StrongHashAlgorithm::Blake2,
+            "0e5751c026e543b2e8ab2eb06099daa1d1e5df47778f7787faab45cdf12fe3a8",
+        ),
+    ];
+
+    for (algorithm, digest) in expected {
+        let hex = algorithm
+            .hash(&[])
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        assert_eq!(hex, digest);
+        assert_eq!(algorithm.hasher().digest_len() * 2, digest.len());
+        assert_eq!(
+            StrongHashAlgorithm::from_id(algorithm.id()),
+            Some(algorithm)
+        );
+        assert_eq!(algorithm.name().parse(), Ok(algorithm));
+    }
+}
@@ -0,0 +1,47 @@
+use std::{fs, path::PathBuf};
+
+use crate::{delta::Delta, types::DeltaOp};
+
+// Empty directory under the system one, removed when dropped
+pub(crate) struct TestDir(pub(crate) PathBuf);
+
+impl TestDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("rdiff-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+
+        TestDir(path)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// Random looking data, `seed` picks the sequence
+pub(crate) fn test_data(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed;
+
+    (0..len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 56) as u8
+        })
+        .collect()
+}
+
+// Bytes the delta sends as they are rather than copying them
+pub(crate) fn literal_len(delta: &Delta) -> usize {
+    delta
+        .ops()
+        .iter()
+        .map(|op| match op {
+            DeltaOp::Literal(bytes) => bytes.len(),
+            DeltaOp::Copy { .. } | DeltaOp::CopyTarget { .. } => 0,
+        })
+        .sum()
+}
@@ -0,0 +1,930 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File, Metadata},
+    io::Write,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app_error::AppError,
+    chunk_processor::InitialEmptyData,
+    delta::Delta,
+    file_format::{
+        EnvelopeReader, EnvelopeWriter, FileHeader, TREE_DELTA_MAGIC, TREE_SIGNATURE_MAGIC,
+    },
+    io_helper::IOHelper,
+    signature::{Signature, SignatureBuilder},
+    types::DeltaOp,
+};
+
+#[cfg(test)]
+use crate::test_support::{test_data, TestDir};
+
+// Directory where patch builds the new files before moving them in place, never part of a tree
+const STAGING_DIR: &str = ".rdiff-staging";
+
+// Chunk size of the empty signature added files are compared against, it only bounds the literals
+const ADDED_FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum TreeEntryKind {
+    Dir,
+    File(Signature),
+    Symlink(String), // Target of the link as it is written, never followed
+}
+
+/// File, directory or symbolic link of a tree, its path relative to the tree root with `/`
+/// separators
+#[derive(Debug)]
+pub struct TreeEntry {
+    pub path: String,
+    pub mode: u32,
+    pub kind: TreeEntryKind,
+}
+
+// Tree signature record, the signature of a file is encoded as a signature file of its own
+#[derive(Serialize, Deserialize)]
+struct EncodedEntry {
+    path: String,
+    mode: u32,
+    signature: Option<Vec<u8>>,
+    link: Option<String>,
+}
+
+// Tree delta record, replayed by phases rather than in order, see `patch_tree`
+#[derive(Serialize, Deserialize, Debug)]
+enum TreeOp {
+    Dir {
+        path: String,
+        mode: u32,
+    },
+    Remove {
+        path: String,
+    },
+    Rename {
+        from: String,
+        to: String,
+        mode: u32,
+    },
+    Mode {
+        path: String,
+        mode: u32,
+    },
+    // Encoded delta of the new file against the basis file at `basis`, or an empty one
+    File {
+        path: String,
+        mode: u32,
+        basis: Option<String>,
+        delta: Vec<u8>,
+    },
+    Symlink {
+        path: String,
+        target: String,
+    },
+}
+
+#[cfg(unix)]
+fn entry_mode(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o7777
+}
+
+// Only the read-only flag exists elsewhere
+#[cfg(not(unix))]
+fn entry_mode(metadata: &Metadata) -> u32 {
+    match metadata.permissions().readonly() {
+        true => 0o555,
+        false => 0o755,
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<(), AppError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(AppError::from)
+}
+
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> Result<(), AppError> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+
+    fs::set_permissions(path, permissions).map_err(AppError::from)
+}
+
+#[cfg(unix)]
+fn make_symlink(target: &str, path: &Path) -> Result<(), AppError> {
+    std::os::unix::fs::symlink(target, path).map_err(AppError::from)
+}
+
+#[cfg(not(unix))]
+fn make_symlink(_target: &str, path: &Path) -> Result<(), AppError> {
+    Err(AppError::UnsupportedFile(format!(
+        "Symbolic link {} can only be restored on unix",
+        path.display()
+    )))
+}
+
+fn symlink_target(path: &Path) -> Result<String, AppError> {
+    fs::read_link(path)?
+        .into_os_string()
+        .into_string()
+        .map_err(|target| {
+            AppError::UnsupportedFile(format!(
+                "Target {:?} of the symbolic link {} is not valid UTF-8",
+                target,
+                path.display()
+            ))
+        })
+}
+
+// The staging directory of an interrupted patch may hold the only copy of the files it moved aside
+fn leftover_staging(path: &Path) -> AppError {
+    AppError::UnsupportedFile(format!(
+        "{} is left over from an interrupted patch and may hold files it moved aside: Please check it and remove it",
+        path.display()
+    ))
+}
+
+// Every file, directory and symbolic link under `root` sorted by path, parents before their children
+fn walk(root: &Path) -> Result<Vec<(String, PathBuf, Metadata)>, AppError> {
+    let mut entries = vec![];
+    let mut pending = vec![(String::new(), root.to_path_buf())];
+
+    while let Some((prefix, dir)) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().into_string().map_err(|name| {
+                AppError::UnsupportedFile(format!("File name {:?} is not valid UTF-8", name))
+            })?;
+
+            // Tree paths never name it, so it can't be signed or sent as part of the tree
+            if prefix.is_empty() && name == STAGING_DIR {
+                return Err(leftover_staging(&entry.path()));
+            }
+
+            let path = format!("{}{}", prefix, name);
+            let metadata = fs::symlink_metadata(entry.path())?;
+
+            if metadata.is_dir() {
+                pending.push((format!("{}/", path), entry.path()));
+            } else if !metadata.is_file() && !metadata.is_symlink() {
+                return Err(AppError::UnsupportedFile(format!(
+                    "{} is neither a regular file, a directory nor a symbolic link",
+                    entry.path().display()
+                )));
+            }
+
+            entries.push((path, entry.path(), metadata));
+        }
+    }
+
+    entries.sort_by(|(left, ..), (right, ..)| left.cmp(right));
+
+    Ok(entries)
+}
+
+// Location of a tree path under `root`, refusing paths that would leave it
+fn tree_path(root: &Path, path: &str) -> Result<PathBuf, AppError> {
+    let relative = Path::new(path);
+    let inside = !path.is_empty()
+        && relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        && relative.components().next() != Some(Component::Normal(STAGING_DIR.as_ref()));
+
+    if !inside {
+        return Err(AppError::CorruptedFile(format!(
+            "Tree path {:?} points outside of the tree",
+            path
+        )));
+    }
+
+    // Trees never hold paths below a symbolic link, one on the way could lead anywhere
+    let mut ancestor = root.to_path_buf();
+
+    for component in relative.parent().into_iter().flat_map(Path::components) {
+        ancestor.push(component);
+
+        if fs::symlink_metadata(&ancestor).is_ok_and(|metadata| metadata.is_symlink()) {
+            return Err(AppError::CorruptedFile(format!(
+                "Tree path {:?} goes through the symbolic link {}",
+                path,
+                ancestor.display()
+            )));
+        }
+    }
+
+    Ok(root.join(relative))
+}
+
+/// Signatures of every file of a directory tree along with the paths and modes of its files and
+/// subdirectories
+#[derive(Debug)]
+pub struct TreeSignature {
+    entries: BTreeMap<String, TreeEntry>,
+}
+
+impl TreeSignature {
+    /// Signs every file under `root` with the builder `new_builder` returns for its size
+    pub fn from_dir<F>(root: &Path, mut new_builder: F) -> Result<Self, AppError>
+    where
+        F: FnMut(usize) -> Result<SignatureBuilder, AppError>,
+    {
+        let mut entries = BTreeMap::new();
+
+        for (path, full_path, metadata) in walk(root)? {
+            let kind = if metadata.is_dir() {
+                TreeEntryKind::Dir
+            } else if metadata.is_symlink() {
+                TreeEntryKind::Symlink(symlink_target(&full_path)?)
+            } else {
+                let mut builder = new_builder(metadata.len() as usize)?;
+                builder.read_from(File::open(&full_path)?)?;
+                TreeEntryKind::File(builder.finish()?)
+            };
+
+            let mode = entry_mode(&metadata);
+            entries.insert(path.clone(), TreeEntry { path, mode, kind });
+        }
+
+        Ok(TreeSignature { entries })
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &TreeEntry> {
+        self.entries.values()
+    }
+
+    // Total size of the files of the tree
+    fn files_len(&self) -> usize {
+        self.entries()
+            .map(|entry| match &entry.kind {
+                TreeEntryKind::File(signature) => signature.chunks_len(),
+                TreeEntryKind::Dir | TreeEntryKind::Symlink(_) => 0,
+            })
+            .sum()
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, AppError> {
+        let header = FileHeader::for_tree(self.files_len());
+        let mut writer = EnvelopeWriter::new(vec![], TREE_SIGNATURE_MAGIC, &header)?;
+
+        for entry in self.entries() {
+            let (signature, link) = match &entry.kind {
+                TreeEntryKind::File(signature) => (Some(signature.to_bytes()?), None),
+                TreeEntryKind::Symlink(target) => (None, Some(target.clone())),
+                TreeEntryKind::Dir => (None, None),
+            };
+
+            writer.write_value(&Some(EncodedEntry {
+                path: entry.path.clone(),
+                mode: entry.mode,
+                signature,
+                link,
+            }))?;
+        }
+
+        writer.write_value(&None::<EncodedEntry>)?;
+        writer.finish()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, AppError> {
+        let (mut reader, _) = EnvelopeReader::new(bytes, TREE_SIGNATURE_MAGIC)?;
+        let mut entries = BTreeMap::new();
+
+        while let Some(entry) = reader.read_value::<Option<EncodedEntry>>()? {
+            let kind = match (entry.signature, entry.link) {
+                (Some(signature), _) => TreeEntryKind::File(Signature::from_bytes(&signature)?),
+                (None, Some(target)) => TreeEntryKind::Symlink(target),
+                (None, None) => TreeEntryKind::Dir,
+            };
+
+            entries.insert(
+                entry.path.clone(),
+                TreeEntry {
+                    path: entry.path,
+                    mode: entry.mode,
+                    kind,
+                },
+            );
+        }
+
+        reader.finish()?;
+
+        Ok(TreeSignature { entries })
+    }
+
+    fn file(&self, path: &str) -> Option<(&Signature, u32)> {
+        match self.entries.get(path) {
+            Some(TreeEntry {
+                kind: TreeEntryKind::File(signature),
+                mode,
+                ..
+            }) => Some((signature, *mode)),
+            _ => None,
+        }
+    }
+}
+
+// Signature of the file at `path` built with the parameters of `signature`, equal to it only when
+// the file holds exactly the basis file
+fn sign_like(signature: &Signature, path: &Path) -> Result<Vec<u8>, AppError> {
+    let mut builder =
+        SignatureBuilder::from_processor(signature.checksums.with_data(InitialEmptyData))?;
+    builder.read_from(File::open(path)?)?;
+
+    builder.finish()?.to_bytes()
+}
+
+/// Encodes into `writer` the changes bringing the tree described by `signature` to the one
+/// under `new_root`: added, removed and renamed entries, mode changes and file deltas
+pub fn tree_delta<W: Write>(
+    signature: &TreeSignature,
+    new_root: &Path,
+    writer: W,
+) -> Result<W, AppError> {
+    let new_entries = walk(new_root)?;
+    let new_files = new_entries
+        .iter()
+        .filter(|(_, _, metadata)| metadata.is_file())
+        .map(|(path, ..)| path.as_str())
+        .collect::<BTreeSet<&str>>();
+
+    // Basis files gone from their path, candidate sources of the renames
+    let mut moved = signature
+        .entries()
+        .filter(|entry| {
+            matches!(entry.kind, TreeEntryKind::File(_)) && !new_files.contains(entry.path.as_str())
+        })
+        .map(|entry| entry.path.as_str())
+        .collect::<BTreeSet<&str>>();
+
+    let mut ops = vec![];
+    let mut new_len = 0;
+
+    for (path, full_path, metadata) in &new_entries {
+        let mode = entry_mode(metadata);
+
+        if metadata.is_dir() {
+            match signature.entries.get(path) {
+                Some(entry) if matches!(entry.kind, TreeEntryKind::Dir) && entry.mode == mode => {}
+                Some(entry) if matches!(entry.kind, TreeEntryKind::Dir) => ops.push(TreeOp::Mode {
+                    path: path.clone(),
+                    mode,
+                }),
+                _ => ops.push(TreeOp::Dir {
+                    path: path.clone(),
+                    mode,
+                }),
+            }
+
+            continue;
+        }
+
+        if metadata.is_symlink() {
+            let target = symlink_target(full_path)?;
+
+            if !matches!(signature.entries.get(path), Some(TreeEntry { kind: TreeEntryKind::Symlink(basis_target), .. }) if *basis_target == target)
+            {
+                ops.push(TreeOp::Symlink {
+                    path: path.clone(),
+                    target,
+                });
+            }
+
+            continue;
+        }
+
+        new_len += metadata.len() as usize;
+
+        if let Some((basis, basis_mode)) = signature.file(path) {
+            let delta = Delta::from_reader(basis, File::open(full_path)?)?;
+            let unchanged = match delta.ops() {
+                [] => basis.chunks_len() == 0,
+                [DeltaOp::Copy { start: 0, len }] => *len == basis.chunks_len(),
+                _ => false,
+            };
+
+            if !unchanged {
+                ops.push(TreeOp::File {
+                    path: path.clone(),
+                    mode,
+                    basis: Some(path.clone()),
+                    delta: delta.to_bytes()?,
+                });
+            } else if mode != basis_mode {
+                ops.push(TreeOp::Mode {
+                    path: path.clone(),
+                    mode,
+                });
+            }
+
+            continue;
+        }
+
+        let mut renamed_from = None;
+
+        // The new file is signed once for each set of parameters among the candidates of its size
+        let mut signed: Vec<(FileHeader, Vec<u8>)> = vec![];
+
+        for from in moved.iter() {
+            let basis = match signature.file(from) {
+                Some((basis, _)) if basis.chunks_len() == metadata.len() as usize => basis,
+                _ => continue,
+            };
+
+            let header = basis.header()?;
+            let index = match signed
+                .iter()
+                .position(|(signed_header, _)| *signed_header == header)
+            {
+                Some(index) => index,
+                None => {
+                    signed.push((header, sign_like(basis, full_path)?));
+                    signed.len() - 1
+                }
+            };
+
+            if signed[index].1 == basis.to_bytes()? {
+                renamed_from = Some(*from);
+                break;
+            }
+        }
+
+        match renamed_from {
+            Some(from) => {
+                moved.remove(from);
+                ops.push(TreeOp::Rename {
+                    from: from.to_string(),
+                    to: path.clone(),
+                    mode,
+                });
+            }
+            None => {
+                let empty = Signature::new(&[], ADDED_FILE_CHUNK_SIZE)?;
+
+                ops.push(TreeOp::File {
+                    path: path.clone(),
+                    mode,
+                    basis: None,
+                    delta: Delta::from_reader(&empty, File::open(full_path)?)?.to_bytes()?,
+                });
+            }
+        }
+    }
+
+    let new_dirs = new_entries
+        .iter()
+        .filter(|(_, _, metadata)| metadata.is_dir())
+        .map(|(path, ..)| path.as_str())
+        .collect::<BTreeSet<&str>>();
+
+    // A link is replaced whenever its target changes
+    let new_symlinks = ops
+        .iter()
+        .filter_map(|op| match op {
+            TreeOp::Symlink { path, .. } => Some(path.as_str()),
+            _ => None,
+        })
+        .collect::<BTreeSet<&str>>();
+    let kept_symlinks = new_entries
+        .iter()
+        .filter(|(path, _, metadata)| {
+            metadata.is_symlink() && !new_symlinks.contains(path.as_str())
+        })
+        .map(|(path, ..)| path.as_str())
+        .collect::<BTreeSet<&str>>();
+
+    for entry in signature.entries() {
+        let kept = match entry.kind {
+            TreeEntryKind::Dir => new_dirs.contains(entry.path.as_str()),
+            TreeEntryKind::File(_) => {
+                new_files.contains(entry.path.as_str()) || !moved.contains(entry.path.as_str())
+            }
+            TreeEntryKind::Symlink(_) => kept_symlinks.contains(entry.path.as_str()),
+        };
+
+        if !kept {
+            ops.push(TreeOp::Remove {
+                path: entry.path.clone(),
+            });
+        }
+    }
+
+    let mut writer = EnvelopeWriter::new(writer, TREE_DELTA_MAGIC, &FileHeader::for_tree(new_len))?;
+
+    for op in &ops {
+        writer.write_value(&Some(op))?;
+    }
+
+    writer.write_value(&None::<&TreeOp>)?;
+    writer.finish()
+}
+
+// Copies the files, directories and symbolic links of `basis_dir` into the empty `output_dir`
+fn copy_tree(basis_dir: &Path, output_dir: &Path) -> Result<(), AppError> {
+    // Files left in the output would end up in the new tree
+    if fs::read_dir(output_dir).is_ok_and(|mut entries| entries.next().is_some()) {
+        return Err(AppError::UnsupportedFile(format!(
+            "{} is not empty: Please patch into a new or empty directory",
+            output_dir.display()
+        )));
+    }
+
+    fs::create_dir_all(output_dir)?;
+    let entries = walk(basis_dir)?;
+
+    for (path, full_path, metadata) in &entries {
+        let target = output_dir.join(path);
+
+        if metadata.is_dir() {
+            fs::create_dir_all(&target)?;
+        } else if metadata.is_symlink() {
+            make_symlink(&symlink_target(full_path)?, &target)?;
+        } else {
+            fs::copy(full_path, &target)?;
+        }
+    }
+
+    for (path, _, metadata) in entries.iter().rev() {
+        if metadata.is_dir() {
+            set_mode(&output_dir.join(path), entry_mode(metadata))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies a tree delta to `basis_dir`, updating it in place when `output_dir` is the same
+/// directory or writing the new tree to `output_dir` otherwise
+pub fn patch_tree(basis_dir: &Path, delta: &[u8], output_dir: &Path) -> Result<(), AppError> {
+    let (mut reader, _) = EnvelopeReader::new(delta, TREE_DELTA_MAGIC)?;
+    let mut ops = vec![];
+
+    while let Some(op) = reader.read_value::<Option<TreeOp>>()? {
+        ops.push(op);
+    }
+
+    reader.finish()?;
+
+    // However the paths are spelled, copying the basis onto itself would empty all of its files
+    let in_place = match (fs::canonicalize(basis_dir), fs::canonicalize(output_dir)) {
+        (Ok(basis_dir), Ok(output_dir)) => basis_dir == output_dir,
+        _ => false,
+    };
+
+    if !in_place {
+        copy_tree(basis_dir, output_dir)?;
+    }
+
+    let root = output_dir;
+    let staging = root.join(STAGING_DIR);
+
+    if fs::symlink_metadata(&staging).is_ok() {
+        return Err(leftover_staging(&staging));
+    }
+
+    fs::create_dir(&staging)?;
+
+    // New contents are built while every basis file is still in place, then renamed files are set aside
+    for (i, op) in ops.iter().enumerate() {
+        match op {
+            TreeOp::File { basis, delta, .. } => {
+                let basis = match basis {
+                    Some(basis) => tree_path(root, basis)?.read_from_file()?,
+                    None => vec![],
+                };
+
+                let patched = Delta::from_bytes(delta)?.apply(&basis)?;
+                staging.join(i.to_string()).write_to_file(patched)?;
+            }
+            TreeOp::Rename { from, .. } => {
+                fs::rename(tree_path(root, from)?, staging.join(i.to_string()))?;
+            }
+            _ => {}
+        }
+    }
+
+    // Children are removed before their parents
+    for op in ops.iter().rev() {
+        if let TreeOp::Remove { path } = op {
+            let path = tree_path(root, path)?;
+
+            match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&path)?,
+                Ok(_) => fs::remove_file(&path)?,
+                Err(_) => {}
+            }
+        }
+    }
+
+    for op in &ops {
+        if let TreeOp::Dir { path, .. } = op {
+            fs::create_dir_all(tree_path(root, path)?)?;
+        }
+    }
+
+    for (i, op) in ops.iter().enumerate() {
+        match op {
+            TreeOp::File { path, mode, .. } | TreeOp::Rename { to: path, mode, .. } => {
+                let target = tree_path(root, path)?;
+
+                fs::rename(staging.join(i.to_string()), &target)?;
+                set_mode(&target, *mode)?;
+            }
+            TreeOp::Mode { path, mode } => {
+                let target = tree_path(root, path)?;
+
+                if fs::symlink_metadata(&target).is_ok_and(|metadata| metadata.is_file()) {
+                    set_mode(&target, *mode)?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Links once every file is in place, so that no other path of the delta is reached through them
+    for op in &ops {
+        if let TreeOp::Symlink { path, target } = op {
+            make_symlink(target, &tree_path(root, path)?)?;
+        }
+    }
+
+    // Directory modes last, a read-only directory would refuse the files moved into it
+    for op in ops.iter().rev() {
+        match op {
+            TreeOp::Dir { path, mode } | TreeOp::Mode { path, mode } => {
+                let target = tree_path(root, path)?;
+
+                if fs::symlink_metadata(&target).is_ok_and(|metadata| metadata.is_dir()) {
+                    set_mode(&target, *mode)?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fs::remove_dir(staging).map_err(AppError::from)
+}
+
+#[cfg(test)]
+fn write_tree(root: &Path, files: &[(&str, &[u8])]) {
+    for (path, data) in files {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+}
+
+// Relative paths and contents of the files under `root`, directories map to None and links to
+// their target
+#[cfg(test)]
+fn read_tree(root: &Path) -> BTreeMap<String, Option<Vec<u8>>> {
+    walk(root)
+        .unwrap()
+        .into_iter()
+        .map(|(path, full_path, metadata)| {
+            let content = if metadata.is_dir() {
+                None
+            } else if metadata.is_symlink() {
+                Some(symlink_target(&full_path).unwrap().into_bytes())
+            } else {
+                Some(fs::read(full_path).unwrap())
+            };
+
+            (path, content)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn diff_trees(old: &Path, new: &Path) -> Vec<u8> {
+    let signature = TreeSignature::from_dir(old, |_| SignatureBuilder::new(1024)).unwrap();
+    let signature = TreeSignature::from_bytes(&signature.to_bytes().unwrap()).unwrap();
+
+    tree_delta(&signature, new, Vec::new()).unwrap()
+}
+
+#[cfg(test)]
+fn sample_trees(dir: &TestDir) -> (PathBuf, PathBuf) {
+    let (old, new) = (dir.0.join("old"), dir.0.join("new"));
+    let big = test_data(50_000, 1);
+    let edited = [&big[..20_000], "edited".as_bytes(), &big[20_000..]].concat();
+    let moved = test_data(10_000, 2);
+
+    write_tree(
+        &old,
+        &[
+            ("kept.txt", b"unchanged"),
+            ("big.bin", &big),
+            ("gone/removed.txt", b"removed"),
+            ("docs/moved.bin", &moved),
+        ],
+    );
+    write_tree(
+        &new,
+        &[
+            ("kept.txt", b"unchanged"),
+            ("big.bin", &edited),
+            ("added/new.txt", b"added"),
+            ("archive/moved.bin", &moved),
+        ],
+    );
+    fs::create_dir_all(new.join("empty")).unwrap();
+
+    (old, new)
+}
+
+#[test]
+fn test_patch_tree_to_output_dir() {
+    let dir = TestDir::new("tree-output");
+    let (old, new) = sample_trees(&dir);
+    let output = dir.0.join("output");
+
+    let delta = diff_trees(&old, &new);
+    patch_tree(&old, &delta, &output).unwrap();
+
+    assert_eq!(read_tree(&output), read_tree(&new));
+    // The basis is left as it was
+    assert!(old.join("gone/removed.txt").exists());
+}
+
+#[test]
+fn test_patch_tree_in_place() {
+    let dir = TestDir::new("tree-in-place");
+    let (old, new) = sample_trees(&dir);
+
+    let delta = diff_trees(&old, &new);
+    patch_tree(&old, &delta, &old).unwrap();
+
+    assert_eq!(read_tree(&old), read_tree(&new));
+    assert!(!old.join(STAGING_DIR).exists());
+}
+
+#[test]
+fn test_patch_tree_in_place_through_other_spelling() {
+    let dir = TestDir::new("tree-spelling");
+    let (old, new) = sample_trees(&dir);
+
+    let delta = diff_trees(&old, &new);
+    patch_tree(&old, &delta, &new.join("../old/.")).unwrap();
+
+    assert_eq!(read_tree(&old), read_tree(&new));
+    assert!(!old.join(STAGING_DIR).exists());
+}
+
+#[test]
+fn test_patch_tree_keeps_leftover_staging_dir() {
+    let dir = TestDir::new("tree-leftover");
+    let (old, new) = sample_trees(&dir);
+    let delta = diff_trees(&old, &new);
+    write_tree(&old, &[(".rdiff-staging/3", b"moved aside")]);
+
+    assert!(matches!(
+        patch_tree(&old, &delta, &old),
+        Err(AppError::UnsupportedFile(_))
+    ));
+    assert_eq!(
+        fs::read(old.join(STAGING_DIR).join("3")).unwrap(),
+        b"moved aside"
+    );
+}
+
+#[test]
+fn test_patch_tree_refuses_non_empty_output_dir() {
+    let dir = TestDir::new("tree-non-empty");
+    let (old, new) = sample_trees(&dir);
+    let output = dir.0.join("output");
+    write_tree(&output, &[("stale.txt", b"stale")]);
+
+    let delta = diff_trees(&old, &new);
+
+    assert!(matches!(
+        patch_tree(&old, &delta, &output),
+        Err(AppError::UnsupportedFile(_))
+    ));
+    assert_eq!(fs::read_dir(&output).unwrap().count(), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_patch_tree_restores_symlinks() {
+    use std::os::unix::fs::symlink;
+
+    let dir = TestDir::new("tree-symlink");
+    let (old, new) = sample_trees(&dir);
+    let output = dir.0.join("output");
+    symlink("kept.txt", old.join("same")).unwrap();
+    symlink("kept.txt", new.join("same")).unwrap();
+    symlink("big.bin", old.join("retargeted")).unwrap();
+    symlink("missing", new.join("retargeted")).unwrap();
+    symlink("gone", old.join("removed")).unwrap();
+    symlink("../kept.txt", new.join("added/link")).unwrap();
+
+    let signature = TreeSignature::from_dir(&old, |_| SignatureBuilder::new(1024)).unwrap();
+    let decoded = TreeSignature::from_bytes(&signature.to_bytes().unwrap()).unwrap();
+    assert!(decoded
+        .entries()
+        .any(|entry| matches!(&entry.kind, TreeEntryKind::Symlink(target) if target == "gone")));
+
+    let delta = diff_trees(&old, &new);
+    patch_tree(&old, &delta, &output).unwrap();
+    assert_eq!(read_tree(&output), read_tree(&new));
+
+    patch_tree(&old, &delta, &old).unwrap();
+    assert_eq!(read_tree(&old), read_tree(&new));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_tree_path_refuses_symlinked_parents() {
+    let dir = TestDir::new("tree-symlink-parent");
+    fs::create_dir_all(&dir.0).unwrap();
+    std::os::unix::fs::symlink("/tmp", dir.0.join("out")).unwrap();
+
+    assert!(tree_path(&dir.0, "out").is_ok());
+    assert!(matches!(
+        tree_path(&dir.0, "out/file"),
+        Err(AppError::CorruptedFile(_))
+    ));
+}
+
+#[test]
+fn test_tree_delta_sends_renames_and_edits_cheaply() {
+    let dir = TestDir::new("tree-rename");
+    let (old, new) = sample_trees(&dir);
+
+    let delta = diff_trees(&old, &new);
+
+    // The moved file is a rename and the edited one mostly copies, no file is sent whole
+    assert!(delta.len() < 10_000, "delta of {} bytes", delta.len());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_patch_tree_applies_mode_changes() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = TestDir::new("tree-mode");
+    let (old, new) = (dir.0.join("old"), dir.0.join("new"));
+    write_tree(&old, &[("run.sh", b"echo hi")]);
+    write_tree(&new, &[("run.sh", b"echo hi")]);
+    fs::set_permissions(new.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+
+    let delta = diff_trees(&old, &new);
+    patch_tree(&old, &delta, &old).unwrap();
+
+    let mode = fs::metadata(old.join("run.sh"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o755);
+}
+
+#[test]
+fn test_tree_path_rejects_escaping_paths() {
+    let root = Path::new("root");
+
+    assert_eq!(tree_path(root, "a/b").unwrap(), root.join("a/b"));
+    assert!(tree_path(root, "../outside").is_err());
+    assert!(tree_path(root, "/etc/passwd").is_err());
+    assert!(tree_path(root, "a/../../outside").is_err());
+    assert!(tree_path(root, STAGING_DIR).is_err());
+}
+
+#[test]
+fn test_tree_refuses_leftover_staging_dir() {
+    let dir = TestDir::new("tree-staging");
+    let (old, new) = sample_trees(&dir);
+    let signature = TreeSignature::from_dir(&old, |_| SignatureBuilder::new(1024)).unwrap();
+    fs::create_dir(new.join(STAGING_DIR)).unwrap();
+
+    assert!(matches!(
+        tree_delta(&signature, &new, Vec::new()),
+        Err(AppError::UnsupportedFile(_))
+    ));
+    assert!(matches!(
+        TreeSignature::from_dir(&new, |_| SignatureBuilder::new(1024)),
+        Err(AppError::UnsupportedFile(_))
+    ));
+}
+
+#[test]
+fn test_tree_delta_matches_renames_among_same_size_files() {
+    let dir = TestDir::new("tree-same-size");
+    let (old, new) = (dir.0.join("old"), dir.0.join("new"));
+    let (first, second) = (test_data(20_000, 3), test_data(20_000, 4));
+
+    write_tree(&old, &[("a.bin", &first), ("b.bin", &second)]);
+    write_tree(&new, &[("x/b.bin", &second), ("y/a.bin", &first)]);
+
+    let delta = diff_trees(&old, &new);
+    assert!(delta.len() < 1000, "delta of {} bytes", delta.len());
+
+    patch_tree(&old, &delta, &old).unwrap();
+    assert_eq!(read_tree(&old), read_tree(&new));
+}
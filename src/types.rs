@@ -1,51 +1,61 @@
-use std::collections::HashMap;
-
-use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Subcommand)]
-pub(crate) enum SubCommand {
-    Signature {
-        #[clap(parse(from_os_str))]
-        old_file: std::path::PathBuf,
-        signature_file: std::path::PathBuf,
-    },
-    Delta {
-        #[clap(parse(from_os_str))]
-        signature_file: std::path::PathBuf,
-        #[clap(parse(from_os_str))]
-        new_file: std::path::PathBuf,
-        delta_file: std::path::PathBuf,
-    },
-}
-
-/// Represenation of the arguments provided by the user
-#[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
-pub struct Args {
-    #[clap(short, long, value_parser, default_value_t = 512)]
-    pub(crate) chunk_size: usize,
-    #[clap(subcommand)]
-    pub(crate) cmd: SubCommand,
-}
-
 // Struct to handle weak + strong checksum operations
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChunkChecksum {
-    pub(crate) ad32: u32,
-    pub(crate) hash: [u8; 32],
+    pub(crate) weak: u32,
+    pub(crate) hash: Vec<u8>,
+    pub(crate) len: usize,
+    pub(crate) hints: Vec<u8>, // Hashes of the chunk sub-blocks one after the other, empty without hints
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct DiffBlock {
-    pub(crate) start: usize,    // Start of diff position in block
-    pub(crate) offset: usize,   // End of diff position in block
-    pub(crate) is_mising: bool, // true if Block not found
-    pub(crate) buf: Vec<u8>,    // Literal bytes to replace in delta
+// Single delta instruction, replayed in order to rebuild the new file
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum DeltaOp {
+    Copy { start: usize, len: usize }, // Range of the basis file to reuse
+    Literal(Vec<u8>),                  // Bytes not found in the basis file
+    CopyTarget { start: usize, len: usize }, // Range of the new file already rebuilt, may overlap the bytes it produces
+}
+
+impl DeltaOp {
+    /// Bytes of the new file the operation produces
+    pub fn len(&self) -> usize {
+        match self {
+            DeltaOp::Copy { len, .. } | DeltaOp::CopyTarget { len, .. } => *len,
+            DeltaOp::Literal(bytes) => bytes.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 pub type ChecksumStore = Vec<ChunkChecksum>;
 
-pub type IndexedChecksumStore = multimap::MultiMap<u32, ([u8; 32], usize)>;
+// Basis chunk location found by its weak checksum
+#[derive(Debug)]
+pub struct IndexedChunk {
+    pub(crate) hash: Vec<u8>,
+    pub(crate) start: usize,
+    pub(crate) len: usize,
+}
+
+// Sub-block hashes of the basis chunk starting at `start`
+#[derive(Debug)]
+pub struct HintedChunk {
+    pub(crate) start: usize,
+    pub(crate) len: usize,
+    pub(crate) hints: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct IndexedChecksumStore {
+    pub(crate) chunks: multimap::MultiMap<u32, IndexedChunk>,
+    pub(crate) tail_len: usize, // Length of the short last chunk, 0 if the basis has none
+    pub(crate) target_window: Option<usize>, // Aligned windows of the new file copies from it stay in, None if disabled
+    pub(crate) hinted: Vec<HintedChunk>, // Chunks in basis order, empty when the signature has no hints
+    pub(crate) unknown_tail_start: Option<usize>, // Start of the last chunk when its length is unknown
+}
 
-pub type DeltaStore = HashMap<usize, DiffBlock>;
+pub type DeltaStore = Vec<DeltaOp>;
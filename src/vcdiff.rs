@@ -0,0 +1,811 @@
+use std::{
+    borrow::Cow,
+    io::{Read, Write},
+};
+
+use adler32::RollingAdler32;
+
+use crate::{
+    app_error::AppError,
+    chunk_processor::{DeltaProducer, IndexedChecksumProducer},
+    signature::Signature,
+    types::DeltaOp,
+};
+
+#[cfg(test)]
+use crate::{
+    chunk_processor::{ChunkProcessor, PatchProducer},
+    test_support::test_data,
+};
+
+const VCDIFF_MAGIC: [u8; 4] = [0xd6, 0xc3, 0xc4, 0x00];
+
+// Header indicator bits, only application headers are understood among them
+const VCD_DECOMPRESS: u8 = 0x01;
+const VCD_CODETABLE: u8 = 0x02;
+const VCD_APPHEADER: u8 = 0x04;
+
+// Window indicator bits, the Adler-32 of the target window is an xdelta3 and open-vcdiff extension
+const VCD_SOURCE: u8 = 0x01;
+const VCD_TARGET: u8 = 0x02;
+const VCD_ADLER32: u8 = 0x04;
+
+/// Target bytes per window written, far below the limits decoders put on windows. Copies from the
+/// new file have to stay within these aligned windows
+pub const VCDIFF_WINDOW_LEN: usize = 1 << 20;
+
+// Largest window target accepted when decoding, the default limit of open-vcdiff
+const MAX_DECODED_WINDOW_LEN: usize = 64 << 20;
+
+// Address caches of the default code table
+const NEAR_CACHE_LEN: usize = 4;
+const SAME_CACHE_LEN: usize = 3;
+const MODE_SELF: u8 = 0;
+const MODE_HERE: u8 = 1;
+const MODE_NEAR: u8 = 2;
+const MODE_SAME: u8 = MODE_NEAR + NEAR_CACHE_LEN as u8;
+
+// First opcodes of the single instructions of the default code table
+const OP_ADD: u8 = 1;
+const OP_COPY: u8 = 19;
+const MAX_ADD_IN_OPCODE: usize = 17;
+const COPY_IN_OPCODE: std::ops::RangeInclusive<usize> = 4..=18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InstructionKind {
+    Noop,
+    Add,
+    Run,
+    Copy,
+}
+
+// Instruction of the code table, a size of 0 is read from the instructions section instead
+#[derive(Debug, Clone, Copy)]
+struct Instruction {
+    kind: InstructionKind,
+    size: u8,
+    mode: u8,
+}
+
+impl Instruction {
+    const NOOP: Instruction = Instruction::new(InstructionKind::Noop, 0, 0);
+
+    const fn new(kind: InstructionKind, size: u8, mode: u8) -> Self {
+        Instruction { kind, size, mode }
+    }
+}
+
+// Default code table of RFC 3284 section 5.6, each opcode standing for one or two instructions
+fn default_code_table() -> Vec<(Instruction, Instruction)> {
+    use InstructionKind::{Add, Copy, Run};
+
+    let mut table = vec![(Instruction::new(Run, 0, 0), Instruction::NOOP)];
+
+    for size in 0..=17 {
+        table.push((Instruction::new(Add, size, 0), Instruction::NOOP));
+    }
+
+    for mode in 0..=8 {
+        table.push((Instruction::new(Copy, 0, mode), Instruction::NOOP));
+
+        for size in 4..=18 {
+            table.push((Instruction::new(Copy, size, mode), Instruction::NOOP));
+        }
+    }
+
+    for mode in 0..=5 {
+        for add_size in 1..=4 {
+            for copy_size in 4..=6 {
+                table.push((
+                    Instruction::new(Add, add_size, 0),
+                    Instruction::new(Copy, copy_size, mode),
+                ));
+            }
+        }
+    }
+
+    for mode in 6..=8 {
+        for add_size in 1..=4 {
+            table.push((
+                Instruction::new(Add, add_size, 0),
+                Instruction::new(Copy, 4, mode),
+            ));
+        }
+    }
+
+    for mode in 0..=8 {
+        table.push((Instruction::new(Copy, 4, mode), Instruction::new(Add, 1, 0)));
+    }
+
+    table
+}
+
+// Variable length integer: 7 bits per byte, most significant first, the high bit set on all but the last
+fn write_int(bytes: &mut Vec<u8>, value: usize) {
+    let mut digits = vec![(value & 0x7f) as u8];
+    let mut rest = value >> 7;
+
+    while rest > 0 {
+        digits.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+
+    bytes.extend(digits.iter().rev());
+}
+
+fn int_len(value: usize) -> usize {
+    let mut bytes = vec![];
+    write_int(&mut bytes, value);
+    bytes.len()
+}
+
+// Sequential reader of a VCDIFF delta or of one of the sections of a window
+struct SectionReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> SectionReader<'a> {
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], AppError> {
+        if self.bytes.len() < len {
+            return Err(AppError::TruncatedFile(String::from(
+                "VCDIFF delta ends in the middle of a field",
+            )));
+        }
+
+        let (field, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+
+        Ok(field)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, AppError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_int(&mut self) -> Result<usize, AppError> {
+        let mut value: usize = 0;
+
+        loop {
+            let byte = self.read_byte()?;
+
+            value = value
+                .checked_mul(0x80)
+                .map(|value| value | (byte & 0x7f) as usize)
+                .ok_or_else(|| AppError::CorruptedFile(String::from("VCDIFF integer overflows")))?;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+}
+
+// Recently copied addresses, both sides update them alike so that copies can refer to them in few bytes
+struct AddressCache {
+    near: [usize; NEAR_CACHE_LEN],
+    next_slot: usize,
+    same: [usize; SAME_CACHE_LEN * 256],
+}
+
+impl AddressCache {
+    fn new() -> Self {
+        AddressCache {
+            near: [0; NEAR_CACHE_LEN],
+            next_slot: 0,
+            same: [0; SAME_CACHE_LEN * 256],
+        }
+    }
+
+    fn update(&mut self, addr: usize) {
+        self.near[self.next_slot] = addr;
+        self.next_slot = (self.next_slot + 1) % NEAR_CACHE_LEN;
+        self.same[addr % self.same.len()] = addr;
+    }
+
+    // Mode and value taking the fewest bytes for the copy of `addr` at the address `here`
+    fn encode(&self, addr: usize, here: usize) -> (u8, usize) {
+        let slot = addr % self.same.len();
+
+        if self.same[slot] == addr {
+            return (MODE_SAME + (slot / 256) as u8, slot % 256);
+        }
+
+        let near = self
+            .near
+            .iter()
+            .enumerate()
+            .filter(|(_, near)| **near <= addr)
+            .map(|(index, near)| (MODE_NEAR + index as u8, addr - near));
+
+        [(MODE_SELF, addr), (MODE_HERE, here - addr)]
+            .into_iter()
+            .chain(near)
+            .min_by_key(|(_, value)| int_len(*value))
+            .unwrap_or((MODE_SELF, addr))
+    }
+
+    fn decode(
+        &self,
+        mode: u8,
+        here: usize,
+        addresses: &mut SectionReader,
+    ) -> Result<usize, AppError> {
+        let addr = match mode {
+            MODE_SELF => Some(addresses.read_int()?),
+            MODE_HERE => here.checked_sub(addresses.read_int()?),
+            MODE_NEAR..MODE_SAME => {
+                self.near[(mode - MODE_NEAR) as usize].checked_add(addresses.read_int()?)
+            }
+            _ => {
+                let slot = (mode - MODE_SAME) as usize * 256 + addresses.read_byte()? as usize;
+                self.same.get(slot).copied()
+            }
+        };
+
+        addr.filter(|addr| *addr < here).ok_or_else(|| {
+            AppError::CorruptedFile(String::from(
+                "VCDIFF copy refers to data past the current position",
+            ))
+        })
+    }
+}
+
+/// Encodes delta operations as a VCDIFF stream using the whole basis as source segment, cut
+/// into windows of bounded size as they come
+pub struct VcdiffWriter<W: Write> {
+    writer: W,
+    source_len: usize,
+    data: Vec<u8>,
+    instructions: Vec<u8>,
+    addresses: Vec<u8>,
+    target_len: usize,   // Bytes produced by the current window
+    window_start: usize, // Offset of the current window in the new file
+    cache: AddressCache,
+}
+
+impl<W: Write> VcdiffWriter<W> {
+    pub fn new(mut writer: W, source_len: usize) -> Result<Self, AppError> {
+        writer.write_all(&VCDIFF_MAGIC)?;
+        writer.write_all(&[0])?;
+
+        Ok(VcdiffWriter {
+            writer,
+            source_len,
+            data: vec![],
+            instructions: vec![],
+            addresses: vec![],
+            target_len: 0,
+            window_start: 0,
+            cache: AddressCache::new(),
+        })
+    }
+
+    /// Starts a delta against the basis file described by `signature`
+    pub fn for_signature(writer: W, signature: &Signature) -> Result<Self, AppError> {
+        VcdiffWriter::new(writer, signature.header()?.basis_len)
+    }
+
+    pub fn write_op(&mut self, op: &DeltaOp) -> Result<(), AppError> {
+        match op {
+            DeltaOp::Literal(bytes) => {
+                let mut bytes = &bytes[..];
+
+                while !bytes.is_empty() {
+                    let (window, rest) = bytes.split_at(bytes.len().min(self.window_room()));
+                    self.push_add(window)?;
+                    bytes = rest;
+                }
+            }
+            DeltaOp::Copy { start, len } => {
+                if start + len > self.source_len {
+                    return Err(AppError::IncompatibleDelta(format!(
+                        "Delta copies bytes {}..{} but the source segment has only {} bytes",
+                        start,
+                        start + len,
+                        self.source_len
+                    )));
+                }
+
+                let (mut start, mut len) = (*start, *len);
+
+                while len > 0 {
+                    let window = len.min(self.window_room());
+                    self.push_copy(start, window)?;
+                    start += window;
+                    len -= window;
+                }
+            }
+            DeltaOp::CopyTarget { len: 0, .. } => {}
+            DeltaOp::CopyTarget { start, len } => {
+                let position = self.window_start + self.target_len;
+
+                // Windows only address the target they produce themselves
+                if *start < self.window_start || *start >= position || *len > self.window_room() {
+                    return Err(AppError::IncompatibleDelta(format!(
+                        "Delta copies bytes {}..{} of the new file from outside of the VCDIFF window of bytes {}..{}",
+                        start,
+                        start + len,
+                        self.window_start,
+                        self.window_start + VCDIFF_WINDOW_LEN
+                    )));
+                }
+
+                self.push_copy(self.source_len + start - self.window_start, *len)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Bytes the current window can still produce
+    fn window_room(&self) -> usize {
+        VCDIFF_WINDOW_LEN - self.target_len
+    }
+
+    fn push_add(&mut self, bytes: &[u8]) -> Result<(), AppError> {
+        match bytes.len() {
+            0 => return Ok(()),
+            len @ 1..=MAX_ADD_IN_OPCODE => self.instructions.push(OP_ADD + len as u8),
+            len => {
+                self.instructions.push(OP_ADD);
+                write_int(&mut self.instructions, len);
+            }
+        }
+
+        self.data.extend_from_slice(bytes);
+        self.advance(bytes.len())
+    }
+
+    fn push_copy(&mut self, addr: usize, len: usize) -> Result<(), AppError> {
+        let (mode, value) = self.cache.encode(addr, self.source_len + self.target_len);
+        self.cache.update(addr);
+
+        let opcode = OP_COPY + 16 * mode;
+
+        match COPY_IN_OPCODE.contains(&len) {
+            true => self.instructions.push(opcode + (len - 3) as u8),
+            false => {
+                self.instructions.push(opcode);
+                write_int(&mut self.instructions, len);
+            }
+        }
+
+        match mode >= MODE_SAME {
+            true => self.addresses.push(value as u8),
+            false => write_int(&mut self.addresses, value),
+        }
+
+        self.advance(len)
+    }
+
+    fn advance(&mut self, len: usize) -> Result<(), AppError> {
+        self.target_len += len;
+
+        match self.target_len >= VCDIFF_WINDOW_LEN {
+            true => self.flush_window(),
+            false => Ok(()),
+        }
+    }
+
+    fn flush_window(&mut self) -> Result<(), AppError> {
+        if self.target_len == 0 {
+            return Ok(());
+        }
+
+        let mut window = vec![];
+
+        match self.source_len {
+            0 => window.push(0),
+            source_len => {
+                window.push(VCD_SOURCE);
+                write_int(&mut window, source_len);
+                write_int(&mut window, 0);
+            }
+        }
+
+        let mut encoding = vec![];
+        write_int(&mut encoding, self.target_len);
+        encoding.push(0);
+        write_int(&mut encoding, self.data.len());
+        write_int(&mut encoding, self.instructions.len());
+        write_int(&mut encoding, self.addresses.len());
+        encoding.append(&mut self.data);
+        encoding.append(&mut self.instructions);
+        encoding.append(&mut self.addresses);
+
+        write_int(&mut window, encoding.len());
+        window.append(&mut encoding);
+
+        self.writer.write_all(&window)?;
+        self.window_start += self.target_len;
+        self.target_len = 0;
+        self.cache = AddressCache::new();
+
+        Ok(())
+    }
+
+    /// Writes the last window and hands the underlying writer back
+    pub fn finish(mut self) -> Result<W, AppError> {
+        self.flush_window()?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+/// Encodes into `writer` the VCDIFF delta of the new data read from `reader`
+pub fn stream_vcdiff_delta<R: Read, W: Write>(
+    signature: &Signature,
+    reader: R,
+    writer: W,
+) -> Result<W, AppError> {
+    let mut writer = VcdiffWriter::for_signature(writer, signature)?;
+
+    signature
+        .checksums
+        .produce_indexed_checksum()
+        .with_target_window(Some(VCDIFF_WINDOW_LEN))
+        .stream_delta(reader, |op| writer.write_op(&op))?;
+
+    writer.finish()
+}
+
+/// Applies a VCDIFF delta, using the default code table and without secondary compression, to
+/// the `basis` data and returns the new file contents
+pub fn patch_vcdiff(basis: &[u8], delta: &[u8]) -> Result<Vec<u8>, AppError> {
+    let mut reader = SectionReader { bytes: delta };
+
+    if reader.take(4).ok() != Some(&VCDIFF_MAGIC[..]) {
+        return Err(AppError::WrongFileKind(String::from(
+            "Expected a VCDIFF delta file",
+        )));
+    }
+
+    let indicator = reader.read_byte()?;
+
+    if indicator & (VCD_DECOMPRESS | VCD_CODETABLE) != 0 {
+        return Err(AppError::UnsupportedFile(String::from(
+            "VCDIFF deltas with secondary compression or custom code tables are not supported",
+        )));
+    }
+
+    if indicator & VCD_APPHEADER != 0 {
+        let len = reader.read_int()?;
+        reader.take(len)?;
+    }
+
+    let table = default_code_table();
+    let mut target = vec![];
+
+    while !reader.is_empty() {
+        let window = decode_window(&mut reader, basis, &target, &table)?;
+        target.extend_from_slice(&window);
+    }
+
+    Ok(target)
+}
+
+// Rebuilds the target bytes of the next window, copying from a segment of the basis or of the target so far
+fn decode_window(
+    reader: &mut SectionReader,
+    basis: &[u8],
+    target: &[u8],
+    table: &[(Instruction, Instruction)],
+) -> Result<Vec<u8>, AppError> {
+    let indicator = reader.read_byte()?;
+
+    let source: Cow<[u8]> = match indicator & (VCD_SOURCE | VCD_TARGET) {
+        0 => Cow::Borrowed(&[]),
+        segment @ (VCD_SOURCE | VCD_TARGET) => {
+            let len = reader.read_int()?;
+            let pos = reader.read_int()?;
+
+            let range = pos.checked_add(len).and_then(|end| match segment {
+                VCD_SOURCE => basis.get(pos..end).map(Cow::Borrowed),
+                _ => target.get(pos..end).map(|range| Cow::Owned(range.to_vec())),
+            });
+
+            range.ok_or_else(|| {
+                AppError::IncompatibleDelta(format!(
+                    "VCDIFF window copies from bytes {}..{} past the end of its source",
+                    pos,
+                    pos.saturating_add(len)
+                ))
+            })?
+        }
+        _ => {
+            return Err(AppError::CorruptedFile(String::from(
+                "VCDIFF window can't copy from both the source and the target",
+            )))
+        }
+    };
+
+    let encoding_len = reader.read_int()?;
+    let mut encoding = SectionReader {
+        bytes: reader.take(encoding_len)?,
+    };
+
+    let target_len = encoding.read_int()?;
+
+    if target_len > MAX_DECODED_WINDOW_LEN {
+        return Err(AppError::UnsupportedFile(format!(
+            "VCDIFF window declares {} target bytes, more than the {} bytes supported",
+            target_len, MAX_DECODED_WINDOW_LEN
+        )));
+    }
+
+    if encoding.read_byte()? != 0 {
+        return Err(AppError::UnsupportedFile(String::from(
+            "VCDIFF windows with compressed sections are not supported",
+        )));
+    }
+
+    let data_len = encoding.read_int()?;
+    let instructions_len = encoding.read_int()?;
+    let addresses_len = encoding.read_int()?;
+
+    let checksum = match indicator & VCD_ADLER32 {
+        0 => None,
+        _ => Some(u32::from_be_bytes(encoding.take(4)?.try_into()?)),
+    };
+
+    let mut data = SectionReader {
+        bytes: encoding.take(data_len)?,
+    };
+    let mut instructions = SectionReader {
+        bytes: encoding.take(instructions_len)?,
+    };
+    let mut addresses = SectionReader {
+        bytes: encoding.take(addresses_len)?,
+    };
+
+    let mut window = Vec::with_capacity(target_len);
+    let mut cache = AddressCache::new();
+
+    while !instructions.is_empty() {
+        let (first, second) = table[instructions.read_byte()? as usize];
+
+        for instruction in [first, second] {
+            if instruction.kind == InstructionKind::Noop {
+                continue;
+            }
+
+            let size = match instruction.size {
+                0 => instructions.read_int()?,
+                size => size as usize,
+            };
+
+            if size > target_len - window.len() {
+                return Err(AppError::CorruptedFile(String::from(
+                    "VCDIFF window produces more bytes than it declares",
+                )));
+            }
+
+            match instruction.kind {
+                InstructionKind::Add => window.extend_from_slice(data.take(size)?),
+                InstructionKind::Run => {
+                    let byte = data.read_byte()?;
+                    window.resize(window.len() + size, byte);
+                }
+                _ => {
+                    let here = source.len() + window.len();
+                    let addr = cache.decode(instruction.mode, here, &mut addresses)?;
+                    cache.update(addr);
+
+                    let end = addr.checked_add(size).ok_or_else(|| {
+                        AppError::CorruptedFile(String::from(
+                            "VCDIFF copy range overflows the address space",
+                        ))
+                    })?;
+
+                    match source.get(addr..end) {
+                        Some(range) => window.extend_from_slice(range),
+                        // Copies reaching into the target may overlap the bytes they produce
+                        None => {
+                            for offset in addr..end {
+                                let byte = match source.get(offset) {
+                                    Some(byte) => *byte,
+                                    None => window[offset - source.len()],
+                                };
+                                window.push(byte);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if window.len() != target_len
+        || !encoding.is_empty()
+        || !data.is_empty()
+        || !addresses.is_empty()
+    {
+        return Err(AppError::CorruptedFile(String::from(
+            "VCDIFF window sections don't match its declared lengths",
+        )));
+    }
+
+    if let Some(checksum) = checksum {
+        if RollingAdler32::from_buffer(&window).hash() != checksum {
+            return Err(AppError::CorruptedFile(String::from(
+                "VCDIFF window doesn't match its checksum",
+            )));
+        }
+    }
+
+    Ok(window)
+}
+
+#[cfg(test)]
+fn encode_ops(source_len: usize, ops: &[DeltaOp]) -> Vec<u8> {
+    let mut writer = VcdiffWriter::new(Vec::new(), source_len).unwrap();
+
+    for op in ops {
+        writer.write_op(op).unwrap();
+    }
+
+    writer.finish().unwrap()
+}
+
+// Example of RFC 3284 section 4.3 with the default code table: COPY 4 from 0, ADD "wxyz", COPY 4
+// from 4, COPY 12 from 24 which overlaps the target it produces, RUN 4 of "z"
+#[cfg(test)]
+const RFC_SOURCE: &[u8] = b"abcdefghijklmnop";
+
+#[cfg(test)]
+const RFC_TARGET: &[u8] = b"abcdwxyzefghefghefghefghzzzz";
+
+#[cfg(test)]
+const RFC_DELTA: [u8; 28] = [
+    0xd6, 0xc3, 0xc4, 0x00, // Magic
+    0x00, // Header indicator
+    0x01, 0x10, 0x00, // Source segment of 16 bytes at 0
+    0x13, // Length of the delta encoding
+    0x1c, 0x00, 0x05, 0x06, 0x03, // Target length, delta indicator, sections lengths
+    b'w', b'x', b'y', b'z', b'z', // Data section
+    0x14, 0x05, 0x14, 0x1c, 0x00, 0x04, // Instructions section
+    0x00, 0x04, 0x18, // Addresses section
+];
+
+#[test]
+fn test_decodes_rfc_example() {
+    assert_eq!(patch_vcdiff(RFC_SOURCE, &RFC_DELTA).unwrap(), RFC_TARGET);
+}
+
+#[test]
+fn test_decodes_application_header_and_checksum() {
+    let checksum = RollingAdler32::from_buffer(RFC_TARGET).hash();
+
+    // Same window as xdelta3 writes it: an application header and the Adler-32 of the target
+    let delta = [
+        &RFC_DELTA[..4],
+        &[VCD_APPHEADER, 0x03],
+        b"app",
+        &[VCD_SOURCE | VCD_ADLER32, 0x10, 0x00, 0x17],
+        &RFC_DELTA[9..14],
+        &checksum.to_be_bytes(),
+        &RFC_DELTA[14..],
+    ]
+    .concat();
+    assert_eq!(patch_vcdiff(RFC_SOURCE, &delta).unwrap(), RFC_TARGET);
+
+    let corrupted = [&delta[..delta.len() - 1], &[0x00]].concat();
+    assert!(patch_vcdiff(RFC_SOURCE, &corrupted).is_err());
+}
+
+#[test]
+fn test_decodes_combined_instructions() {
+    assert_eq!(default_code_table().len(), 256);
+
+    // Opcode 163 adds 1 byte then copies 4 in self mode, opcode 247 does the reverse
+    let delta = [
+        0xd6, 0xc3, 0xc4, 0x00, 0x00, 0x01, 0x08, 0x00, 0x0b, 0x0a, 0x00, 0x02, 0x02, 0x02, b'X',
+        b'Y', 0xa3, 0xf7, 0x00, 0x04,
+    ];
+
+    assert_eq!(patch_vcdiff(b"abcdefgh", &delta).unwrap(), b"XabcdefghY");
+}
+
+#[test]
+fn test_vcdiff_round_trip_across_windows() {
+    let basis = test_data(3 * VCDIFF_WINDOW_LEN, 6);
+    let ops = [
+        DeltaOp::Copy {
+            start: 1000,
+            len: 2 * VCDIFF_WINDOW_LEN + 17,
+        },
+        DeltaOp::Literal(test_data(70_000, 7)),
+        // Repeated addresses go through the near and same caches
+        DeltaOp::Copy { start: 5, len: 3 },
+        DeltaOp::Copy { start: 5, len: 300 },
+        DeltaOp::Copy { start: 20, len: 18 },
+        DeltaOp::Literal(b"z".to_vec()),
+        // Copies from the third window, the second one overlapping the bytes it produces
+        DeltaOp::CopyTarget {
+            start: 2 * VCDIFF_WINDOW_LEN + 17,
+            len: 5000,
+        },
+        DeltaOp::CopyTarget {
+            start: 2 * VCDIFF_WINDOW_LEN + 75_338,
+            len: 50,
+        },
+        DeltaOp::Copy {
+            start: 0,
+            len: basis.len(),
+        },
+    ];
+
+    let delta = encode_ops(basis.len(), &ops);
+    let expected = ChunkProcessor::new(1)
+        .with_data(Vec::from(ops))
+        .produce_patch(&basis)
+        .unwrap();
+
+    assert!(delta.len() < 71_000, "{} bytes", delta.len());
+    assert_eq!(patch_vcdiff(&basis, &delta).unwrap(), expected);
+}
+
+#[test]
+fn test_vcdiff_delta_from_signature() {
+    let basis = test_data(100_000, 8);
+    let new_data = [&basis[..40_000], b"inserted", &basis[30_000..]].concat();
+    let signature = Signature::new(&basis, 1024).unwrap();
+
+    let delta = stream_vcdiff_delta(&signature, &new_data[..], Vec::new()).unwrap();
+    assert_eq!(patch_vcdiff(&basis, &delta).unwrap(), new_data);
+
+    // Without a basis there is no source segment and only literals
+    let delta = encode_ops(0, &[DeltaOp::Literal(new_data.clone())]);
+    assert_eq!(patch_vcdiff(&[], &delta).unwrap(), new_data);
+}
+
+#[test]
+fn test_vcdiff_rejects_bad_deltas() {
+    assert!(patch_vcdiff(RFC_SOURCE, &RFC_DELTA[..RFC_DELTA.len() - 1]).is_err());
+    assert!(patch_vcdiff(RFC_SOURCE, &[&RFC_DELTA[..], &[0x00]].concat()).is_err());
+    assert!(patch_vcdiff(RFC_SOURCE, &RFC_DELTA[1..]).is_err());
+    assert!(patch_vcdiff(&RFC_SOURCE[..8], &RFC_DELTA).is_err());
+
+    let mut writer = VcdiffWriter::new(Vec::new(), 10).unwrap();
+    assert!(writer
+        .write_op(&DeltaOp::Copy { start: 5, len: 6 })
+        .is_err());
+
+    writer.write_op(&DeltaOp::Literal(b"abc".to_vec())).unwrap();
+    writer
+        .write_op(&DeltaOp::CopyTarget { start: 2, len: 8 })
+        .unwrap();
+    assert!(writer
+        .write_op(&DeltaOp::CopyTarget { start: 11, len: 1 })
+        .is_err());
+}
+
+#[test]
+fn test_vcdiff_rejects_oversized_window() {
+    // A single RUN of a window declaring 1 TiB of target bytes
+    let target_len = 1 << 40;
+    let mut instructions = vec![0];
+    write_int(&mut instructions, target_len);
+
+    let mut encoding = vec![];
+    write_int(&mut encoding, target_len);
+    encoding.extend_from_slice(&[0, 1]);
+    write_int(&mut encoding, instructions.len());
+    encoding.push(0);
+    encoding.push(b'x');
+    encoding.extend_from_slice(&instructions);
+
+    let mut delta = [&VCDIFF_MAGIC[..], &[0, 0]].concat();
+    write_int(&mut delta, encoding.len());
+    delta.extend_from_slice(&encoding);
+
+    assert!(matches!(
+        patch_vcdiff(&[], &delta),
+        Err(AppError::UnsupportedFile(_))
+    ));
+}
@@ -0,0 +1,45 @@
+#!/usr/bin/env python3
+"""Writes the inputs of the librsync fixtures of this directory.
+
+The signatures and the delta are not written here: regenerate.sh makes them
+with librsync's own rdiff from these inputs.
+"""
+
+from pathlib import Path
+
+HERE = Path(__file__).parent
+
+
+def test_data(length, seed):
+    state = seed
+    out = bytearray()
+    for _ in range(length):
+        state = (state * 6364136223846793005 + 1442695040888963407) % 2**64
+        out.append(state >> 56)
+    return bytes(out)
+
+
+def main():
+    basis = test_data(66_000, 21)
+    # Sections of the new file: ranges copied from the basis, or new bytes
+    sections = [
+        (100, 2900),
+        test_data(10, 22),
+        (65_600, 400),
+        test_data(300, 23),
+        (0, 200),
+        test_data(64, 24),
+        (2048, 63_952),
+        test_data(100, 25),
+    ]
+    new = b"".join(
+        section if isinstance(section, bytes) else basis[section[0]:section[0] + section[1]]
+        for section in sections
+    )
+
+    (HERE / "basis.bin").write_bytes(basis)
+    (HERE / "new.bin").write_bytes(new)
+
+
+if __name__ == "__main__":
+    main()
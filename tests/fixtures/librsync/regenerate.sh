@@ -0,0 +1,14 @@
+#!/bin/sh
+# Rewrites the signatures and the delta of this directory with librsync's own rdiff, over the
+# basis.bin and new.bin inputs written by generate.py. The rdiff version used is kept in
+# LIBRSYNC_VERSION next to them.
+set -eu
+cd "$(dirname "$0")"
+
+rdiff signature -b 2048 -S 16 -H md4 -R rollsum basis.bin md4.sig
+rdiff signature -b 2048 -S 32 -H blake2 -R rollsum basis.bin blake2.sig
+rdiff signature -b 2048 -S 8 -H md4 -R rabinkarp basis.bin rk-md4.sig
+rdiff signature -b 2048 -S 12 -H blake2 -R rabinkarp basis.bin rk-blake2.sig
+rdiff delta rk-blake2.sig new.bin new.delta
+
+rdiff --version | head -n 1 > LIBRSYNC_VERSION